@@ -138,6 +138,18 @@ impl DBusConnection {
     }
 }
 
+/// `source_type` bit of a window
+pub const SOURCE_TYPE_WINDOW: u32 = 2;
+
+/// The stream the user picked
+pub struct PortalStream {
+    pub node_id: u32,
+    /// Bitmask, the portal may leave it out
+    pub source_type: Option<u32>,
+    /// Width and height, the portal may leave it out
+    pub size: Option<(i32, i32)>,
+}
+
 impl ScreenCastProxy {
     pub fn new(bus: gio::DBusConnection) -> Self {
         let proxy = gio::DBusProxy::new_sync(
@@ -231,7 +243,7 @@ impl ScreenCastProxy {
         if response.0 != 0 { Err(()) } else { Ok(()) }
     }
 
-    pub fn start(&self) -> Result<u32, ()> {
+    pub fn start(&self) -> Result<PortalStream, ()> {
         let response = call_request_proxy_signal(&self.bus, |handle_token| {
             self.proxy
                 .call_sync(
@@ -255,8 +267,12 @@ impl ScreenCastProxy {
         } else {
             let arrs: Option<Vec<(u32, HashMap<String, glib::Variant>)>> =
                 response.1.get("streams").unwrap().get();
-            let arrs = arrs.unwrap();
-            Ok(arrs[0].0)
+            let (node_id, properties) = arrs.unwrap().into_iter().next().unwrap();
+            Ok(PortalStream {
+                node_id,
+                source_type: properties.get("source_type").and_then(|v| v.get()),
+                size: properties.get("size").and_then(|v| v.get()),
+            })
         }
     }
 }
@@ -1,7 +1,10 @@
 use iced::{Element, Task};
-use std::{cell::LazyCell, sync::LazyLock};
+use std::{cell::LazyCell, env, sync::LazyLock};
 
-use server::conn::TcpSendReceiveClient;
+use server::{
+    conn::{Features, TcpSendReceiveClient},
+    tls,
+};
 
 mod dbus;
 mod macros;
@@ -9,17 +12,61 @@ mod pipeline;
 mod ui;
 mod video;
 
-pub static TPC_SEND_RECEIVE_CLIENT: LazyLock<TcpSendReceiveClient> =
-    LazyLock::new(|| TcpSendReceiveClient::new("localhost".to_string(), 3000));
+pub static TPC_SEND_RECEIVE_CLIENT: LazyLock<TcpSendReceiveClient> = LazyLock::new(|| {
+    let mut client = TcpSendReceiveClient::new("localhost".to_string(), 3000);
+    // keeps rpc payloads readable, for debugging
+    if env::var_os("BSS_JSON_RPC").is_some() {
+        client = client.with_features(Features::SUPPORTED.difference(Features::MSGPACK));
+    }
+    match tls_trust() {
+        Some(trust) => client.with_tls(trust).unwrap(),
+        None => client,
+    }
+});
+
+/// Tls is used when one of `BSS_TLS_CA`, `BSS_TLS_FINGERPRINT`
+/// or `BSS_TLS_KNOWN_HOSTS` is set
+fn tls_trust() -> Option<tls::Trust> {
+    if let Ok(v) = env::var("BSS_TLS_CA") {
+        return Some(tls::Trust::Ca(v.into()));
+    }
+    if let Ok(v) = env::var("BSS_TLS_FINGERPRINT") {
+        return Some(tls::Trust::Pinned(tls::fingerprint_from_hex(&v).unwrap()));
+    }
+    if let Ok(v) = env::var("BSS_TLS_KNOWN_HOSTS") {
+        return Some(tls::Trust::Tofu(v.into()));
+    }
+    None
+}
 
 struct App {
     screen: ui::Screen,
 }
 
 impl App {
-    fn new() -> Self {
-        Self {
-            screen: ui::Screen::MainMenu(ui::MainMenu::new()),
+    fn new() -> (Self, Task<ui::Message>) {
+        (
+            Self {
+                screen: ui::Screen::MainMenu(ui::MainMenu::new()),
+            },
+            ui::MainMenu::refresh().map(ui::Message::MainMenuMessage),
+        )
+    }
+
+    /// Switches to the lobby, or shows why it couldn't be joined
+    fn enter_lobby(
+        &mut self,
+        lobby: anyhow::Result<(ui::Lobby, Task<ui::LobbyMessage>)>,
+    ) -> Task<ui::Message> {
+        match lobby {
+            Ok((lobby, task)) => {
+                self.screen = ui::Screen::Lobby(lobby);
+                task.map(ui::Message::LobbyMessage)
+            }
+            Err(e) => {
+                self.screen.main_menu().set_join_error(&e);
+                Task::none()
+            }
         }
     }
 
@@ -34,20 +81,36 @@ impl App {
         match message {
             ui::Message::MainMenuMessage(v) => match v {
                 ui::MainMenuMessage::CreateLobby => {
-                    let (lobby, task) = smol::block_on(async {
-                        ui::Lobby::new("foo-bar".to_string()).await.unwrap()
-                    });
-                    self.screen = ui::Screen::Lobby(lobby);
-                    task.map(ui::Message::LobbyMessage)
+                    let menu = self.screen.main_menu();
+                    let (credentials, profile) = (menu.credentials(), menu.profile());
+                    let lobby = ui::Lobby::create(menu.is_public(), credentials, profile);
+                    self.enter_lobby(smol::block_on(lobby))
                 }
-            },
-            ui::Message::LobbyMessage(v) => match v {
-                ui::LobbyMessage::Leave => {
-                    self.screen = ui::Screen::MainMenu(ui::MainMenu::new());
-                    Task::none()
+                ui::MainMenuMessage::JoinLobby(id) => {
+                    let menu = self.screen.main_menu();
+                    let (credentials, profile) = (menu.credentials(), menu.profile());
+                    self.enter_lobby(smol::block_on(ui::Lobby::new(id, credentials, profile)))
                 }
-                v => self.screen.lobby().update(v).map(ui::Message::LobbyMessage),
+                v => self
+                    .screen
+                    .main_menu()
+                    .update(v)
+                    .map(ui::Message::MainMenuMessage),
             },
+            ui::Message::LobbyMessage(v) => {
+                // already queued when we left the lobby
+                let ui::Screen::Lobby(lobby) = &mut self.screen else {
+                    return Task::none();
+                };
+                match v {
+                    ui::LobbyMessage::Leave => {
+                        lobby.leave();
+                        self.screen = ui::Screen::MainMenu(ui::MainMenu::new());
+                        ui::MainMenu::refresh().map(ui::Message::MainMenuMessage)
+                    }
+                    v => lobby.update(v).map(ui::Message::LobbyMessage),
+                }
+            }
         }
     }
 }
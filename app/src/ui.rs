@@ -1,8 +1,10 @@
 use std::{
     io,
-    net::SocketAddrV4,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
     os::fd::{self, AsRawFd, RawFd},
     str::FromStr,
+    sync::{Arc, Mutex},
+    time,
 };
 
 use futures::{channel::mpsc, prelude::*, stream};
@@ -10,20 +12,55 @@ use gio::prelude::*;
 use gstreamer::{self as gst, prelude::*};
 use gstreamer_app::{self as gst_app};
 use iced::{
-    Element, Length, Task,
+    Color, Element, Length, Task,
     advanced::{self, widget::operation::map},
     task,
-    widget::{button, column, container, image, row},
+    widget::{button, checkbox, column, container, image, row, scrollable, text, text_input},
 };
 use smol::net::UdpSocket;
 
 use crate::{dbus, macros::dbg_err, pipeline, video};
 use server::{
-    conn::TcpId,
-    rpc::{JoinLobbyData, RpcUserClient, rpc_user_notify_stream},
-    state::LobbyInfoData,
+    codec::Codec,
+    conn::{self, TcpId},
+    mux::MuxChannel,
+    relay::RelayToken,
+    rpc::{
+        CallError, CreateInviteData, CreateLobbyData, Heartbeat, JoinLobbyData, LobbyAccessData,
+        Notification, RpcConn, RpcErrorCode, RpcUserClient, SendChatData, TargetData, VoidRet,
+        rpc_user_notify_stream,
+    },
+    session::ResumeToken,
+    state::{
+        self, ChatMessage, Credentials, LobbyEvent, LobbyInfoData, MemberId, Profile, PublicLobby,
+        SourceKind, StreamEndReason, StreamMetadata, StreamState, StreamUpdate, UdpToken,
+        VideoCodec,
+    },
 };
 
+/// Colors the main menu offers for the profile
+const PROFILE_COLORS: [u32; 6] = [0xe06c75, 0xe5c07b, 0x98c379, 0x56b6c2, 0x61afef, 0xc678dd];
+
+fn stream_label(state: StreamState) -> &'static str {
+    match state {
+        StreamState::Idle => "",
+        StreamState::Starting => "starting",
+        StreamState::Live => "live",
+        StreamState::Paused => "paused",
+        StreamState::Ended(StreamEndReason::Stopped) => "ended",
+        StreamState::Ended(StreamEndReason::StoppedByHost) => "stopped by host",
+        StreamState::Ended(StreamEndReason::Failed) => "stream failed",
+    }
+}
+
+fn profile_text(profile: &Profile) -> text::Text<'_> {
+    let [_, r, g, b] = profile.color.to_be_bytes();
+    text(profile.display_name.as_str()).color(Color::from_rgb8(r, g, b))
+}
+
+/// How long invites made from the lobby screen stay valid
+const INVITE_TTL_SECS: u64 = 24 * 60 * 60;
+
 #[derive(Debug, Clone)]
 pub enum Message {
     // Start,
@@ -45,14 +82,6 @@ impl Screen {
             panic!("main_menu assert");
         }
     }
-
-    pub fn lobby(&mut self) -> &mut Lobby {
-        if let Self::Lobby(v) = self {
-            v
-        } else {
-            panic!("lobby assert");
-        }
-    }
 }
 
 #[derive(Debug, Clone)]
@@ -121,43 +150,233 @@ impl VideoStream {
 #[derive(Debug, Clone)]
 pub enum MainMenuMessage {
     CreateLobby,
+    JoinLobby(String),
+    DisplayNameChanged(String),
+    ColorPicked(u32),
+    PasswordChanged(String),
+    InviteChanged(String),
+    LobbyIdChanged(String),
+    PublicToggled(bool),
+    RefreshLobbies,
+    LobbiesListed(Result<Vec<PublicLobby>, String>),
+}
+
+pub struct MainMenu {
+    display_name: String,
+    color: u32,
+    password: String,
+    invite: String,
+    /// Typed in to join a lobby that isn't listed
+    lobby_id: String,
+    /// Whether a created lobby is listed
+    public: bool,
+    lobbies: Vec<PublicLobby>,
+    join_error: Option<String>,
 }
 
-pub struct MainMenu {}
+/// Asks the server for its public lobbies on a connection of its own
+async fn list_lobbies() -> anyhow::Result<Vec<PublicLobby>> {
+    let conn = crate::TPC_SEND_RECEIVE_CLIENT.create().await?;
+    let rpc = RpcUserClient::new(rpc_conn(conn.sender, &conn.hello, conn.heartbeat));
+    Ok(rpc.list_lobbies(VoidRet {}).await?)
+}
 
 impl MainMenu {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            display_name: String::new(),
+            color: PROFILE_COLORS[0],
+            password: String::new(),
+            invite: String::new(),
+            lobby_id: String::new(),
+            public: true,
+            lobbies: Vec::new(),
+            join_error: None,
+        }
+    }
+
+    pub fn refresh() -> Task<MainMenuMessage> {
+        Task::perform(list_lobbies(), |v| {
+            MainMenuMessage::LobbiesListed(v.map_err(|e| e.to_string()))
+        })
+    }
+
+    pub fn is_public(&self) -> bool {
+        self.public
+    }
+
+    pub fn profile(&self) -> Profile {
+        Profile {
+            display_name: self.display_name.trim().to_string(),
+            color: self.color,
+            avatar_hash: None,
+        }
+    }
+
+    /// Empty fields aren't sent
+    pub fn credentials(&self) -> Credentials {
+        let non_empty = |v: &str| (!v.is_empty()).then(|| v.to_string());
+        Credentials {
+            password: non_empty(&self.password),
+            invite: non_empty(self.invite.trim()),
+        }
+    }
+
+    /// Asks for credentials if that is why the join failed
+    pub fn set_join_error(&mut self, e: &anyhow::Error) {
+        let code = match e.downcast_ref::<CallError>() {
+            Some(CallError::Rpc(e)) => Some(e.code),
+            _ => None,
+        };
+        self.join_error = Some(match code {
+            Some(RpcErrorCode::CredentialsRequired) => {
+                "This lobby needs a password or an invite".to_string()
+            }
+            Some(RpcErrorCode::InvalidCredentials) => {
+                "Wrong password or invalid invite".to_string()
+            }
+            Some(RpcErrorCode::Banned) => "You are banned from this lobby".to_string(),
+            Some(RpcErrorCode::LobbyFull) => "This lobby is full".to_string(),
+            _ => format!("Joining failed: {e}"),
+        });
+    }
+
+    pub fn update(&mut self, message: MainMenuMessage) -> Task<MainMenuMessage> {
+        match message {
+            MainMenuMessage::DisplayNameChanged(v) => self.display_name = v,
+            MainMenuMessage::ColorPicked(v) => self.color = v,
+            MainMenuMessage::PasswordChanged(v) => self.password = v,
+            MainMenuMessage::InviteChanged(v) => self.invite = v,
+            MainMenuMessage::LobbyIdChanged(v) => self.lobby_id = v,
+            MainMenuMessage::PublicToggled(v) => self.public = v,
+            MainMenuMessage::RefreshLobbies => return Self::refresh(),
+            MainMenuMessage::LobbiesListed(Ok(v)) => self.lobbies = v,
+            MainMenuMessage::LobbiesListed(Err(e)) => println!("listing lobbies failed: {e}"),
+            MainMenuMessage::CreateLobby | MainMenuMessage::JoinLobby(_) => {
+                unreachable!("should be handled above")
+            }
+        }
+        Task::none()
+    }
+
+    fn view_lobbies(&self, can_join: bool) -> Element<'_, MainMenuMessage> {
+        let lobbies = self.lobbies.iter().map(|v| {
+            row!(
+                text(v.id.as_str()),
+                text(v.host_name.as_str()),
+                text(format!("{}/{} members", v.members, v.limits.max_members)),
+                text(format!(
+                    "{}/{} streaming",
+                    v.streamers, v.limits.max_streamers
+                )),
+                v.locked.then(|| text("password")),
+                button("Join")
+                    .on_press_maybe(can_join.then(|| MainMenuMessage::JoinLobby(v.id.clone()))),
+            )
+            .spacing(8)
+            .into()
+        });
+        column!(
+            row!(
+                "Public lobbies",
+                button("Refresh").on_press(MainMenuMessage::RefreshLobbies)
+            ),
+            scrollable(column(lobbies)).height(Length::Fixed(200.0)),
+        )
+        .into()
     }
 
     pub fn view(&self) -> Element<'_, MainMenuMessage> {
+        let colors = PROFILE_COLORS.map(|v| {
+            let [_, r, g, b] = v.to_be_bytes();
+            let label = if v == self.color { "[x]" } else { "[ ]" };
+            button(text(label).color(Color::from_rgb8(r, g, b)))
+                .on_press(MainMenuMessage::ColorPicked(v))
+                .into()
+        });
+        let can_join = !self.display_name.trim().is_empty();
+        let lobby_id = self.lobby_id.trim();
+
         container(column!(
-            button("Create Lobby").on_press(MainMenuMessage::CreateLobby)
+            text_input("Display name", &self.display_name)
+                .on_input(MainMenuMessage::DisplayNameChanged),
+            row(colors),
+            text_input("Password", &self.password)
+                .secure(true)
+                .on_input(MainMenuMessage::PasswordChanged),
+            text_input("Invite", &self.invite).on_input(MainMenuMessage::InviteChanged),
+            row!(
+                button("Create Lobby")
+                    .on_press_maybe(can_join.then_some(MainMenuMessage::CreateLobby)),
+                checkbox(self.public)
+                    .label("Public")
+                    .on_toggle(MainMenuMessage::PublicToggled),
+            ),
+            row!(
+                text_input("Lobby id", &self.lobby_id).on_input(MainMenuMessage::LobbyIdChanged),
+                button("Join").on_press_maybe(
+                    (can_join && !lobby_id.is_empty())
+                        .then(|| MainMenuMessage::JoinLobby(lobby_id.to_string()))
+                ),
+            ),
+            self.join_error.as_deref().map(text),
+            self.view_lobbies(can_join),
         ))
         .center(Length::Fill)
         .into()
     }
 }
 
+/// How our stream is encoded, the pipeline is built to match what we publish
+const STREAM_PAYLOAD_TYPE: u8 = 96;
+const STREAM_FRAMERATE: u32 = 30;
+const STREAM_BITRATE_KBPS: u32 = 4000;
+/// Sent when the portal doesn't say how big the source is
+const DEFAULT_STREAM_SIZE: (u32, u32) = (1920, 1080);
+
 struct MyStream {
     stream: VideoStream,
+    /// Published with `start_stream`
+    metadata: StreamMetadata,
     udpsink: gst::Element,
     udp_valve: gst::Element,
+    /// Reported live to the server once the first frame came through
+    live: bool,
+    paused: bool,
     // drop
     _pipewire_fd: fd::OwnedFd,
     _screen_cast_proxy: dbus::ScreenCastProxy,
 }
 
 impl MyStream {
-    fn new(sink_socket_fd: RawFd) -> (Self, Task<VideoStreamMessage>) {
+    fn new(sink_socket_fd: RawFd, title: String) -> (Self, Task<VideoStreamMessage>) {
         let bus_connection = dbus::bus_connection_get_session();
         let screen_cast_proxy = dbus::ScreenCastProxy::new(bus_connection);
 
         screen_cast_proxy.select_sources().unwrap();
 
-        let pipewire_node_id = screen_cast_proxy.start().unwrap();
+        let portal_stream = screen_cast_proxy.start().unwrap();
+        let pipewire_node_id = portal_stream.node_id;
         let pipewire_fd = screen_cast_proxy.open_pipewire_remote();
 
+        let (width, height) = match portal_stream.size {
+            Some((width, height)) if width > 0 && height > 0 => (width as u32, height as u32),
+            _ => DEFAULT_STREAM_SIZE,
+        };
+        let metadata = StreamMetadata {
+            codec: VideoCodec::H264,
+            payload_type: STREAM_PAYLOAD_TYPE,
+            width,
+            height,
+            framerate: STREAM_FRAMERATE,
+            bitrate_kbps: STREAM_BITRATE_KBPS,
+            title,
+            source: match portal_stream.source_type {
+                Some(v) if v & dbus::SOURCE_TYPE_WINDOW != 0 => SourceKind::Window,
+                _ => SourceKind::Monitor,
+            },
+        };
+
         let pipewiresrc = gst::ElementFactory::make("pipewiresrc")
             .property("do-timestamp", true)
             .property("fd", pipewire_fd.as_raw_fd())
@@ -180,11 +399,29 @@ impl MyStream {
             .unwrap();
         let queue2 = gst::ElementFactory::make("queue").build().unwrap();
 
+        // holds the sent video to the published resolution and framerate
+        let videorate = gst::ElementFactory::make("videorate").build().unwrap();
+        let capsfilter = gst::ElementFactory::make("capsfilter")
+            .property(
+                "caps",
+                &gst::Caps::from_str(&format!(
+                    "video/x-raw,width={},height={},framerate={}/1",
+                    metadata.width, metadata.height, metadata.framerate
+                ))
+                .unwrap(),
+            )
+            .build()
+            .unwrap();
+
         let encoder = gst::ElementFactory::make("x264enc")
             .property_from_str("tune", "zerolatency")
+            .property("bitrate", metadata.bitrate_kbps)
+            .build()
+            .unwrap();
+        let payloader = gst::ElementFactory::make("rtph264pay")
+            .property("pt", metadata.payload_type as u32)
             .build()
             .unwrap();
-        let payloader = gst::ElementFactory::make("rtph264pay").build().unwrap();
 
         let appsink = gst_app::AppSink::builder()
             .drop(true)
@@ -213,6 +450,8 @@ impl MyStream {
                 &queue2,
                 &udp_valve,
                 &videoconvertscale2,
+                &videorate,
+                &capsfilter,
                 &encoder,
                 &payloader,
                 &udpsink,
@@ -228,8 +467,11 @@ impl MyStream {
         (
             Self {
                 stream,
+                metadata,
                 udpsink,
                 udp_valve,
+                live: false,
+                paused: false,
                 _pipewire_fd: pipewire_fd,
                 _screen_cast_proxy: screen_cast_proxy,
             },
@@ -245,20 +487,55 @@ impl MyStream {
         self.stream.view()
     }
 
-    fn set_udp_sink(&self, address: &SocketAddrV4) {
-        self.udp_valve.set_property("drop", false);
+    /// Stops sending frames to the peer, the local preview keeps running
+    fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.udp_valve.set_property("drop", paused);
+    }
 
-        println!("setting udpsink to {}", address.to_string());
-        self.udpsink.set_property("clients", address.to_string());
+    /// Sends to `addresses` only, the watchers of the stream
+    fn set_udp_sink(&self, addresses: &[SocketAddr]) {
+        self.udp_valve.set_property("drop", self.paused);
+
+        println!("setting udpsink to {:?}", addresses);
+        // "clients" can't parse ipv6 hosts
+        self.udpsink.emit_by_name::<()>("clear", &[]);
+        for address in addresses {
+            self.udpsink.emit_by_name::<()>(
+                "add",
+                &[&address.ip().to_string(), &(address.port() as i32)],
+            );
+        }
     }
 }
 
 struct PeerStream {
     stream: VideoStream,
+    /// A frame came through, so the path to the streamer works
+    receiving: bool,
+    /// Who is streaming
+    profile: Profile,
+    /// What the pipeline was built for, a restarted stream may need another one
+    metadata: StreamMetadata,
+}
+
+/// Depayloader and decoder for `codec`
+fn depayloader_decoder(codec: VideoCodec) -> (&'static str, &'static str) {
+    match codec {
+        VideoCodec::H264 => ("rtph264depay", "avdec_h264"),
+        VideoCodec::Vp8 => ("rtpvp8depay", "vp8dec"),
+        VideoCodec::Vp9 => ("rtpvp9depay", "vp9dec"),
+        VideoCodec::Av1 => ("rtpav1depay", "av1dec"),
+    }
 }
 
 impl PeerStream {
-    fn new(addr: &SocketAddrV4, src_socket_fd: RawFd) -> (Self, Task<VideoStreamMessage>) {
+    fn new(
+        addr: &SocketAddr,
+        src_socket_fd: RawFd,
+        profile: Profile,
+        metadata: StreamMetadata,
+    ) -> (Self, Task<VideoStreamMessage>) {
         let udpsrc = gst::ElementFactory::make("udpsrc")
             .property("socket", unsafe {
                 // this closed????
@@ -268,14 +545,19 @@ impl PeerStream {
             .property_from_str("port", &addr.port().to_string())
             .property(
                 "caps",
-                &gst::Caps::from_str("application/x-rtp,media=video,payload=96,clock-rate=90000")
-                    .unwrap(),
+                &gst::Caps::from_str(&format!(
+                    "application/x-rtp,media=video,payload={},clock-rate=90000,encoding-name={}",
+                    metadata.payload_type,
+                    metadata.codec.encoding_name()
+                ))
+                .unwrap(),
             )
             .build()
             .unwrap();
 
-        let h264depay = gst::ElementFactory::make("rtph264depay").build().unwrap();
-        let h264parse = gst::ElementFactory::make("avdec_h264").build().unwrap();
+        let (depayloader, decoder) = depayloader_decoder(metadata.codec);
+        let depayloader = gst::ElementFactory::make(depayloader).build().unwrap();
+        let decoder = gst::ElementFactory::make(decoder).build().unwrap();
         let converter = gst::ElementFactory::make("videoconvertscale")
             .build()
             .unwrap();
@@ -290,8 +572,8 @@ impl PeerStream {
         let gst_pipeline = pipeline::Pipeline::new(gst::Pipeline::new())
             .link([
                 &udpsrc,
-                &h264depay,
-                &h264parse,
+                &depayloader,
+                &decoder,
                 &converter,
                 &appsink.clone().into(),
             ])
@@ -303,7 +585,15 @@ impl PeerStream {
             message_rx,
         );
 
-        (PeerStream { stream }, task)
+        (
+            PeerStream {
+                stream,
+                receiving: false,
+                profile,
+                metadata,
+            },
+            task,
+        )
     }
 
     fn update(&mut self, message: VideoStreamMessage) -> Task<VideoStreamMessage> {
@@ -311,65 +601,269 @@ impl PeerStream {
     }
 
     fn view(&self) -> Element<'_, VideoStreamMessage> {
-        self.stream.view()
+        let metadata = &self.metadata;
+        let source = match metadata.source {
+            SourceKind::Monitor => "screen",
+            SourceKind::Window => "window",
+        };
+        column!(
+            row!(
+                profile_text(&self.profile),
+                text(if metadata.title.is_empty() {
+                    source.to_string()
+                } else {
+                    format!("{} ({source})", metadata.title)
+                }),
+            )
+            .spacing(8),
+            text(format!(
+                "{}x{} {} fps, {} kbps",
+                metadata.width, metadata.height, metadata.framerate, metadata.bitrate_kbps
+            )),
+            self.stream.view(),
+        )
+        .into()
     }
 }
 
 pub struct Lobby {
     id: String,
     my_stream: Option<MyStream>,
+    /// Client whose stream we subscribed to
+    watching: Option<MemberId>,
     peer_stream: Option<PeerStream>,
     server_client: ServerClient,
+    /// Local copy of the lobby, kept up to date by the server's deltas
+    model: Option<LobbyInfoData>,
+    /// Kept to join again after a reconnect
+    credentials: Credentials,
+    profile: Profile,
+    new_password: String,
+    invite: Option<String>,
+    /// Last call that failed in a way worth telling the user
+    error: Option<String>,
+    /// Oldest first, as much as the server keeps plus what came since
+    chat: Vec<ChatMessage>,
+    chat_input: String,
+    /// Published with the next stream
+    stream_title: String,
+    /// Tasks that feed this lobby, aborted when it is dropped
+    tasks: Vec<task::Handle>,
+    /// Notifications of the current connection, replaced on reconnect
+    notify: Option<task::Handle>,
 }
 
 #[derive(Debug, Clone)]
 pub enum LobbyMessage {
     StartStream,
     StopStream,
+    PauseStream(bool),
     Leave,
     VideoStreamMessage(VideoStreamMessage),
     PeerStreamMessage(VideoStreamMessage),
-    RpcNotify(Result<LobbyInfoData, String>),
+    RpcNotify(Result<Notification, String>),
+    NewPasswordChanged(String),
+    SetPassword,
+    CreateInvite,
+    Kick(MemberId),
+    Ban(MemberId),
+    StopClientStream(MemberId),
+    TransferHost(MemberId),
+    ChatInputChanged(String),
+    SendChat,
+    StreamTitleChanged(String),
+    Watch(MemberId),
+    StopWatching,
+    /// Falls back to the relay if the watched stream shows nothing yet
+    CheckPeerTraffic,
+    RelayKeepalive,
+    Reconnected(Result<Reconnection, String>),
 }
 
+/// Connection made by the reconnect task, taken by the lobby once it gets it
+#[derive(Debug, Clone)]
+pub struct Reconnection(Arc<Mutex<Option<conn::TcpSenderReceiver>>>);
+
 struct ServerClient {
     rpc: RpcUserClient,
     udp_socket: UdpSocket,
     tcp_id: TcpId,
+    resume_token: ResumeToken,
+    /// Kept to close the whole connection when leaving
+    channel: MuxChannel,
+    /// Our port on the server's relay and what claims it, once the direct path failed
+    relay: Option<(SocketAddr, RelayToken)>,
 }
 
-impl ServerClient {
-    async fn new() -> anyhow::Result<(Self, Task<LobbyMessage>)> {
-        let (tcp_id, sender, receiver) = crate::TPC_SEND_RECEIVE_CLIENT.create().await?;
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_BACKOFF: time::Duration = time::Duration::from_millis(200);
+
+/// How long a watched stream can stay dark before we ask for the relay
+const RELAY_FALLBACK_AFTER: time::Duration = time::Duration::from_secs(5);
+/// Keeps the nat mapping to our relay port open
+const RELAY_KEEPALIVE: time::Duration = time::Duration::from_secs(15);
+
+/// Pings with the timing the server asked for, if heartbeats were negotiated
+fn rpc_conn(
+    channel: MuxChannel,
+    hello: &conn::Hello,
+    heartbeat: Option<Heartbeat>,
+) -> RpcConn<MuxChannel> {
+    let rpc_conn = RpcConn::new(channel, Codec::negotiated(hello));
+    match heartbeat {
+        Some(v) => rpc_conn.with_heartbeat(v),
+        None => rpc_conn,
+    }
+}
 
-        let task = Task::stream(rpc_user_notify_stream(receiver.into()))
-            .map(|v| LobbyMessage::RpcNotify(v.map_err(|e| e.to_string())));
+fn notify_task(
+    receiver: MuxChannel,
+    hello: &conn::Hello,
+    heartbeat: Option<Heartbeat>,
+) -> Task<LobbyMessage> {
+    Task::stream(rpc_user_notify_stream(rpc_conn(receiver, hello, heartbeat)))
+        .map(|v| LobbyMessage::RpcNotify(v.map_err(|e| e.to_string())))
+}
 
-        let rpc = RpcUserClient::new(sender.into());
-        let udp_socket = UdpSocket::bind("127.0.0.1:0").await?;
+impl ServerClient {
+    async fn new() -> anyhow::Result<(Self, Task<LobbyMessage>)> {
+        let conn::TcpSenderReceiver {
+            id: tcp_id,
+            hello,
+            heartbeat,
+            resume_token,
+            sender,
+            receiver,
+            ..
+        } = crate::TPC_SEND_RECEIVE_CLIENT.create().await?;
+        let task = notify_task(receiver, &hello, heartbeat);
+
+        let rpc = RpcUserClient::new(rpc_conn(sender.clone(), &hello, heartbeat));
+        let udp_socket = match UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await {
+            Ok(v) => v,
+            // ipv6 is disabled on this host
+            Err(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+        };
 
         Ok((
             Self {
                 rpc,
                 udp_socket,
                 tcp_id,
+                resume_token,
+                channel: sender,
+                relay: None,
             },
             task,
         ))
     }
 
+    /// Connects again, retrying with backoff, and asks to take over the old session.
+    /// Doesn't touch the client so it can run as a task, see [`Self::use_connection`]
+    fn reconnect(&self) -> impl Future<Output = anyhow::Result<conn::TcpSenderReceiver>> + use<> {
+        let (tcp_id, resume_token) = (self.tcp_id, self.resume_token);
+        async move {
+            let mut backoff = RECONNECT_BACKOFF;
+            let mut attempt = 1;
+            loop {
+                match crate::TPC_SEND_RECEIVE_CLIENT
+                    .resume(&tcp_id, &resume_token)
+                    .await
+                {
+                    Ok(v) => return Ok(v),
+                    Err(e) if attempt < RECONNECT_ATTEMPTS => {
+                        println!("reconnect attempt {attempt} failed: {e}");
+                        smol::Timer::after(backoff).await;
+                        backoff *= 2;
+                        attempt += 1;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+
+    /// Switches to a connection from [`Self::reconnect`].
+    /// Returns whether the session was resumed and the new notify task
+    fn use_connection(&mut self, conn: conn::TcpSenderReceiver) -> (bool, Task<LobbyMessage>) {
+        self.rpc = RpcUserClient::new(rpc_conn(conn.sender.clone(), &conn.hello, conn.heartbeat));
+        self.channel = conn.sender;
+        self.tcp_id = conn.id;
+        self.resume_token = conn.resume_token;
+        (
+            conn.resumed,
+            notify_task(conn.receiver, &conn.hello, conn.heartbeat),
+        )
+    }
+
+    /// Maps `addr` into the address family of the udp socket,
+    /// `None` if the socket can't reach it
+    fn udp_peer_addr(&self, addr: SocketAddr) -> Option<SocketAddr> {
+        match (self.udp_socket.local_addr().ok()?, addr) {
+            (SocketAddr::V6(_), SocketAddr::V4(v4)) => {
+                Some(SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()))
+            }
+            (SocketAddr::V4(_), SocketAddr::V6(v6)) => v6
+                .ip()
+                .to_ipv4_mapped()
+                .map(|ip| SocketAddr::new(ip.into(), v6.port())),
+            _ => Some(addr),
+        }
+    }
+
     async fn join_lobby(&mut self, data: JoinLobbyData) -> anyhow::Result<()> {
-        self.rpc.join_lobby(data).await?;
-        self.udp_socket
-            .send_to(&self.tcp_id, "127.0.0.1:4000")
-            .await?;
+        let ret = self.rpc.join_lobby(data).await?;
+        self.register_udp(ret.udp_port, &ret.udp_token).await
+    }
+
+    /// Returns the id the server picked
+    async fn create_lobby(&mut self, data: CreateLobbyData) -> anyhow::Result<String> {
+        let ret = self.rpc.create_lobby(data).await?;
+        self.register_udp(ret.udp_port, &ret.udp_token).await?;
+        Ok(ret.id)
+    }
+
+    /// Lets the server learn our udp address, which it shares with the lobby
+    /// `port` on the server, as the udp socket reaches it
+    async fn server_udp_addr(&self, port: u16) -> anyhow::Result<SocketAddr> {
+        smol::net::resolve((crate::TPC_SEND_RECEIVE_CLIENT.host(), port))
+            .await?
+            .into_iter()
+            .find_map(|v| self.udp_peer_addr(v))
+            .ok_or_else(|| anyhow::anyhow!("server udp address is unreachable"))
+    }
+
+    async fn register_udp(&mut self, udp_port: u16, token: &UdpToken) -> anyhow::Result<()> {
+        let server_addr = self.server_udp_addr(udp_port).await?;
+        self.udp_socket.send_to(token, server_addr).await?;
+        Ok(())
+    }
+
+    /// How the rest of the lobby knows us
+    fn member_id(&self) -> MemberId {
+        state::member_id(&self.tcp_id)
+    }
+
+    /// Has lobby members send to us through the server's relay from now on
+    async fn allocate_relay(&mut self) -> anyhow::Result<()> {
+        let allocation = self.rpc.allocate_relay(VoidRet {}).await?;
+        let relay_addr = self.server_udp_addr(allocation.port).await?;
+        self.relay = Some((relay_addr, allocation.token));
+        self.keep_relay().await
+    }
+
+    /// Claims our relay port again, from wherever the nat maps us to now
+    async fn keep_relay(&self) -> anyhow::Result<()> {
+        if let Some((relay_addr, token)) = &self.relay {
+            self.udp_socket.send_to(token, relay_addr).await?;
+        }
         Ok(())
     }
 
-    async fn send_hello(&mut self, addresses: &[SocketAddrV4]) -> anyhow::Result<()> {
+    async fn send_hello(&mut self, addresses: &[SocketAddr]) -> anyhow::Result<()> {
         let self_im = &self;
         let futs = addresses
-            .into_iter()
+            .iter()
             .map(|addr| async move {
                 self_im.udp_socket.send_to(b"hello", addr).await?;
                 io::Result::Ok(())
@@ -383,34 +877,171 @@ impl ServerClient {
 }
 
 impl Lobby {
-    pub async fn new(id: String) -> anyhow::Result<(Self, Task<LobbyMessage>)> {
+    pub async fn new(
+        id: String,
+        credentials: Credentials,
+        profile: Profile,
+    ) -> anyhow::Result<(Self, Task<LobbyMessage>)> {
         let (mut client, task) = ServerClient::new().await?;
-        client.join_lobby(JoinLobbyData { id: id.clone() }).await?;
+        client
+            .join_lobby(JoinLobbyData {
+                id: id.clone(),
+                credentials: credentials.clone(),
+                profile: profile.clone(),
+            })
+            .await?;
+        let mut lobby = Self::with_client(id, client, credentials, profile);
+        let task = lobby.listen(task);
+        Ok((lobby, task))
+    }
 
-        Ok((
-            Self {
-                id,
-                server_client: client,
-                my_stream: None,
-                peer_stream: None,
-            },
-            task,
-        ))
+    /// Creates a lobby with a server picked id and joins it as the host
+    pub async fn create(
+        public: bool,
+        credentials: Credentials,
+        profile: Profile,
+    ) -> anyhow::Result<(Self, Task<LobbyMessage>)> {
+        let (mut client, task) = ServerClient::new().await?;
+        let id = client
+            .create_lobby(CreateLobbyData {
+                credentials: credentials.clone(),
+                profile: profile.clone(),
+                public,
+            })
+            .await?;
+        let mut lobby = Self::with_client(id, client, credentials, profile);
+        let task = lobby.listen(task);
+        Ok((lobby, task))
+    }
+
+    fn with_client(
+        id: String,
+        server_client: ServerClient,
+        credentials: Credentials,
+        profile: Profile,
+    ) -> Self {
+        Self {
+            id,
+            server_client,
+            my_stream: None,
+            watching: None,
+            peer_stream: None,
+            model: None,
+            credentials,
+            profile,
+            new_password: String::new(),
+            invite: None,
+            error: None,
+            chat: Vec::new(),
+            chat_input: String::new(),
+            stream_title: String::new(),
+            tasks: Vec::new(),
+            notify: None,
+        }
+    }
+
+    /// Ties `task` to the lobby, so none of its messages outlive a leave
+    fn guard(&mut self, task: Task<LobbyMessage>) -> Task<LobbyMessage> {
+        let (task, handle) = task.abortable();
+        self.tasks.push(handle.abort_on_drop());
+        task
+    }
+
+    /// Like [`Self::guard`] for the notify task, dropping the previous one
+    fn listen(&mut self, task: Task<LobbyMessage>) -> Task<LobbyMessage> {
+        let (task, handle) = task.abortable();
+        self.notify = Some(handle.abort_on_drop());
+        task
+    }
+
+    /// Tells the server we left and closes the connection,
+    /// instead of lingering in the lobby until the session expires
+    pub fn leave(&mut self) {
+        if let Err(e) = smol::block_on(self.server_client.rpc.leave_lobby(VoidRet {})) {
+            println!("leave lobby failed: {e}");
+        }
+        self.server_client.channel.shutdown();
+        self.notify = None;
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+    }
+
+    fn is_host(&self) -> bool {
+        self.model
+            .as_ref()
+            .is_some_and(|v| v.host == self.server_client.member_id())
     }
 
     fn view_clients(&self) -> Element<'_, LobbyMessage> {
-        column!("Clients").into()
+        let host_actions = |id: MemberId| {
+            self.is_host().then(|| {
+                row!(
+                    button("Kick").on_press(LobbyMessage::Kick(id)),
+                    button("Ban").on_press(LobbyMessage::Ban(id)),
+                    button("Stop stream").on_press(LobbyMessage::StopClientStream(id)),
+                    button("Make host").on_press(LobbyMessage::TransferHost(id)),
+                )
+            })
+        };
+        let watch = |id: MemberId, stream: StreamState| {
+            if self.watching == Some(id) {
+                Some(button("Stop watching").on_press(LobbyMessage::StopWatching))
+            } else {
+                stream
+                    .is_active()
+                    .then(|| button("Watch").on_press(LobbyMessage::Watch(id)))
+            }
+        };
+        let clients = self.model.iter().flat_map(|model| {
+            model.clients.iter().map(|v| {
+                let streaming = stream_label(v.stream);
+                let host = if v.id == model.host { "host" } else { "" };
+                row!(
+                    profile_text(&v.profile),
+                    text(host),
+                    text(streaming),
+                    watch(v.id, v.stream),
+                    host_actions(v.id)
+                )
+                .spacing(8)
+                .into()
+            })
+        });
+        let header = self.model.as_ref().map(|model| {
+            // the model leaves us out
+            let members = model.clients.len() + 1;
+            let streamers = model
+                .clients
+                .iter()
+                .filter(|v| v.stream.is_active())
+                .count()
+                + self.my_stream.is_some() as usize;
+            text(format!(
+                "{members}/{} members, {streamers}/{} streaming",
+                model.limits.max_members, model.limits.max_streamers
+            ))
+        });
+        column!("Clients", header, column(clients)).into()
     }
 
     fn view_my_stream(&self) -> Element<'_, LobbyMessage> {
         container(match &self.my_stream {
             Some(v) => Element::<LobbyMessage>::from(column!(
-                button("Stop Stream").on_press(LobbyMessage::StopStream),
+                row!(
+                    button("Stop Stream").on_press(LobbyMessage::StopStream),
+                    button(if v.paused { "Resume" } else { "Pause" })
+                        .on_press(LobbyMessage::PauseStream(!v.paused)),
+                ),
                 v.view().map(LobbyMessage::VideoStreamMessage),
             )),
-            None => button("Start stream")
-                .on_press(LobbyMessage::StartStream)
-                .into(),
+            None => row!(
+                text_input("Stream title", &self.stream_title)
+                    .on_input(LobbyMessage::StreamTitleChanged)
+                    .on_submit(LobbyMessage::StartStream),
+                button("Start stream").on_press(LobbyMessage::StartStream),
+            )
+            .into(),
         })
         .width(Length::Fill)
         .into()
@@ -422,16 +1053,54 @@ impl Lobby {
             .map(|v| v.view().map(LobbyMessage::PeerStreamMessage))
     }
 
+    /// Only shown to the host, the server refuses everyone else
+    fn view_access(&self) -> Option<Element<'_, LobbyMessage>> {
+        if !self.is_host() {
+            return None;
+        }
+        Some(
+            column!(
+                row!(
+                    text_input("New password", &self.new_password)
+                        .secure(true)
+                        .on_input(LobbyMessage::NewPasswordChanged),
+                    button("Set password").on_press(LobbyMessage::SetPassword),
+                    button("Create invite").on_press(LobbyMessage::CreateInvite),
+                ),
+                self.invite.as_deref().map(text),
+            )
+            .into(),
+        )
+    }
+
+    fn view_chat(&self) -> Element<'_, LobbyMessage> {
+        let messages = self
+            .chat
+            .iter()
+            .map(|v| text(format!("{}: {}", v.display_name, v.text)).into());
+        column!(
+            scrollable(column(messages)).height(Length::Fill),
+            text_input("Message", &self.chat_input)
+                .on_input(LobbyMessage::ChatInputChanged)
+                .on_submit(LobbyMessage::SendChat),
+        )
+        .width(Length::Fixed(300.0))
+        .into()
+    }
+
     pub fn view(&self) -> Element<'_, LobbyMessage> {
         column!(
             container(row!(
                 self.id.as_str(),
                 button("Leave").on_press(LobbyMessage::Leave)
             )),
+            self.view_access(),
+            self.error.as_deref().map(text),
             row!(
                 self.view_my_stream(),
                 self.view_peer_stream(),
                 self.view_clients(),
+                self.view_chat(),
             ),
         )
         .into()
@@ -442,77 +1111,430 @@ impl Lobby {
         info: LobbyInfoData,
         tasks: &mut Vec<Task<LobbyMessage>>,
     ) -> anyhow::Result<()> {
-        let client_addresses = info
-            .clients
-            .iter()
-            .map(|v| v.udp_addr)
-            .filter(|v| v.is_some())
-            .map(|v| match v {
-                Some(v) => v,
-                None => unreachable!(),
-            })
-            .collect::<Vec<_>>();
-        println!("hole punching {:?}", &client_addresses);
-        self.server_client.send_hello(&client_addresses).await?;
+        let me = self.server_client.member_id();
+        let watched = self
+            .watching
+            .and_then(|id| info.clients.iter().find(|v| v.id == id));
+        let mut watcher_addresses = Vec::new();
+        for client in info.clients.iter().filter(|v| v.watching.contains(&me)) {
+            // watchers we can't reach directly take our video through their relay port
+            let address = match (client.relay_port, client.udp_addr) {
+                (Some(port), _) => Some(self.server_client.server_udp_addr(port).await?),
+                (None, Some(udp_addr)) => self.server_client.udp_peer_addr(udp_addr),
+                (None, None) => None,
+            };
+            watcher_addresses.extend(address);
+        }
 
-        if let Some(client) = info.clients.get(0) {
-            if client.is_streaming {
-                let (peer_stream, task) = PeerStream::new(
-                    &client.udp_addr.unwrap(),
-                    self.server_client.udp_socket.as_raw_fd(),
-                );
-                println!("starting peer stream");
-                tasks.push(task.map(LobbyMessage::PeerStreamMessage));
-                self.peer_stream = Some(peer_stream);
+        // video only flows to watchers and from the stream we watch
+        let mut peer_addresses = watcher_addresses.clone();
+        peer_addresses.extend(
+            watched
+                .and_then(|v| v.udp_addr)
+                .and_then(|v| self.server_client.udp_peer_addr(v)),
+        );
+        println!("hole punching {:?}", &peer_addresses);
+        self.server_client.send_hello(&peer_addresses).await?;
+
+        if let Some(my_stream) = &self.my_stream {
+            my_stream.set_udp_sink(&watcher_addresses);
+        }
+
+        match watched {
+            Some(client) if client.stream.is_active() => {
+                if client.stream == StreamState::Live
+                    && let Some(metadata) = &client.stream_metadata
+                    && self
+                        .peer_stream
+                        .as_ref()
+                        .is_none_or(|v| &v.metadata != metadata)
+                    // otherwise started by the update that brings the streamer's address
+                    && let Some(source) = client
+                        .udp_addr
+                        .and_then(|v| self.server_client.udp_peer_addr(v))
+                        // relayed video comes from our relay port
+                        .or(self.server_client.relay.map(|(addr, _)| addr))
+                {
+                    let (peer_stream, task) = PeerStream::new(
+                        &source,
+                        self.server_client.udp_socket.as_raw_fd(),
+                        client.profile.clone(),
+                        metadata.clone(),
+                    );
+                    println!("starting peer stream");
+                    tasks.push(task.map(LobbyMessage::PeerStreamMessage));
+                    if self.server_client.relay.is_none() {
+                        let check = Task::perform(smol::Timer::after(RELAY_FALLBACK_AFTER), |_| {
+                            LobbyMessage::CheckPeerTraffic
+                        });
+                        tasks.push(self.guard(check));
+                    }
+                    self.peer_stream = Some(peer_stream);
+                }
+            }
+            // the server drops the subscription once the stream ends or its streamer leaves
+            _ => {
+                self.watching = None;
+                self.peer_stream = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn stop_watching(&mut self) {
+        self.peer_stream = None;
+        if let Some(id) = self.watching.take() {
+            let unsubscribe = self.server_client.rpc.unsubscribe(TargetData { id });
+            if let Err(e) = smol::block_on(unsubscribe) {
+                println!("stop watching failed: {e}");
             }
         }
+    }
 
-        if let Some(address) = client_addresses.get(0) {
+    /// Drops the current connection and makes a new one in the background,
+    /// [`LobbyMessage::Reconnected`] brings it back
+    fn reconnect(&mut self) -> Task<LobbyMessage> {
+        // the old connection may still be up, its notifications would only race the new ones
+        self.notify = None;
+        self.server_client.channel.shutdown();
+        let task = Task::perform(self.server_client.reconnect(), |v| {
+            LobbyMessage::Reconnected(
+                v.map(|v| Reconnection(Arc::new(Mutex::new(Some(v)))))
+                    .map_err(|e| e.to_string()),
+            )
+        });
+        self.guard(task)
+    }
+
+    /// Restores the lobby state on a new connection
+    /// when the server no longer had our session
+    async fn use_connection(
+        &mut self,
+        conn: conn::TcpSenderReceiver,
+    ) -> anyhow::Result<Task<LobbyMessage>> {
+        let (resumed, task) = self.server_client.use_connection(conn);
+        println!("reconnected, resumed: {resumed}");
+        if !resumed {
+            // the lobby may have been recreated with a fresh sequence
+            self.model = None;
+            self.server_client
+                .join_lobby(JoinLobbyData {
+                    id: self.id.clone(),
+                    credentials: self.credentials.clone(),
+                    profile: self.profile.clone(),
+                })
+                .await?;
             if let Some(my_stream) = &self.my_stream {
-                my_stream.set_udp_sink(address);
+                self.server_client
+                    .rpc
+                    .start_stream(my_stream.metadata.clone())
+                    .await?;
+                let update = match (my_stream.live, my_stream.paused) {
+                    (_, true) => Some(StreamUpdate::Paused),
+                    (true, false) => Some(StreamUpdate::Live),
+                    (false, false) => None,
+                };
+                if let Some(update) = update {
+                    self.server_client.rpc.update_stream(update).await?;
+                }
+            }
+            // the old allocation went with the session, the keepalive task keeps running
+            if self.server_client.relay.take().is_some()
+                && let Err(e) = self.server_client.allocate_relay().await
+            {
+                println!("relay allocation failed: {e}");
+            }
+            if let Some(id) = self.watching {
+                let subscribe = self.server_client.rpc.subscribe(TargetData { id }).await;
+                if let Err(e) = subscribe {
+                    println!("watching again failed: {e}");
+                    self.watching = None;
+                    self.peer_stream = None;
+                }
             }
         }
+        Ok(self.listen(task))
+    }
 
-        Ok(())
+    /// Applies a notification to the local lobby model,
+    /// fetching a snapshot when a delta was missed
+    async fn update_model(&mut self, notification: Notification) -> anyhow::Result<LobbyInfoData> {
+        match notification {
+            Notification::LobbyInfo(v) => {
+                if self.model.as_ref().is_none_or(|model| model.seq <= v.seq) {
+                    self.model = Some(v);
+                }
+            }
+            Notification::LobbyDelta(v) => {
+                let applied = self.model.as_mut().is_some_and(|model| model.apply(v));
+                if !applied {
+                    println!("missed a lobby update, fetching a snapshot");
+                    self.model = Some(self.server_client.rpc.lobby_snapshot(VoidRet {}).await?);
+                }
+            }
+            // not part of the model
+            Notification::Chat(_) | Notification::ChatHistory(_) => {}
+        }
+        Ok(self.model.clone().unwrap())
     }
 
     pub fn update(&mut self, message: LobbyMessage) -> Task<LobbyMessage> {
         match message {
-            LobbyMessage::VideoStreamMessage(v) => self
-                .my_stream
-                .as_mut()
-                .unwrap()
-                .update(v)
-                .map(LobbyMessage::VideoStreamMessage),
-            LobbyMessage::PeerStreamMessage(v) => self
-                .peer_stream
-                .as_mut()
-                .unwrap()
-                .update(v)
-                .map(LobbyMessage::PeerStreamMessage),
+            LobbyMessage::VideoStreamMessage(v) => {
+                // frames still in flight after the stream was stopped
+                let Some(my_stream) = self.my_stream.as_mut() else {
+                    return Task::none();
+                };
+                if !my_stream.live
+                    && !my_stream.paused
+                    && matches!(
+                        v,
+                        VideoStreamMessage::PipelineMessage(video::VideoMessage::Frame(..))
+                    )
+                {
+                    my_stream.live = true;
+                    let live = self.server_client.rpc.update_stream(StreamUpdate::Live);
+                    if let Err(e) = smol::block_on(live) {
+                        println!("reporting stream live failed: {e}");
+                    }
+                }
+                my_stream.update(v).map(LobbyMessage::VideoStreamMessage)
+            }
+            LobbyMessage::PeerStreamMessage(v) => {
+                // frames still in flight after we stopped watching
+                let Some(peer_stream) = self.peer_stream.as_mut() else {
+                    return Task::none();
+                };
+                if matches!(
+                    v,
+                    VideoStreamMessage::PipelineMessage(video::VideoMessage::Frame(..))
+                ) {
+                    peer_stream.receiving = true;
+                }
+                peer_stream.update(v).map(LobbyMessage::PeerStreamMessage)
+            }
             LobbyMessage::StartStream => {
-                let (my_stream, task) = MyStream::new(self.server_client.udp_socket.as_raw_fd());
+                // the metadata is only known once the user picked a source
+                let (my_stream, task) = MyStream::new(
+                    self.server_client.udp_socket.as_raw_fd(),
+                    self.stream_title.clone(),
+                );
+                let start = self
+                    .server_client
+                    .rpc
+                    .start_stream(my_stream.metadata.clone());
+                match smol::block_on(start) {
+                    Ok(_) => self.error = None,
+                    Err(CallError::Rpc(e)) if e.code == RpcErrorCode::TooManyStreamers => {
+                        self.error = Some("Too many people are streaming already".to_string());
+                        return Task::none();
+                    }
+                    Err(e) => {
+                        println!("start stream failed: {e}");
+                        return Task::none();
+                    }
+                }
                 self.my_stream = Some(my_stream);
-                smol::block_on(async { self.server_client.rpc.start_stream().await }).unwrap();
                 task.map(LobbyMessage::VideoStreamMessage)
             }
             LobbyMessage::StopStream => {
+                if let Err(e) = smol::block_on(self.server_client.rpc.stop_stream(VoidRet {})) {
+                    println!("stop stream failed: {e}");
+                }
                 self.my_stream = None;
                 Task::none()
             }
+            LobbyMessage::PauseStream(paused) => {
+                let Some(my_stream) = self.my_stream.as_mut() else {
+                    return Task::none();
+                };
+                my_stream.set_paused(paused);
+                let update = if paused {
+                    StreamUpdate::Paused
+                } else {
+                    StreamUpdate::Live
+                };
+                if let Err(e) = smol::block_on(self.server_client.rpc.update_stream(update)) {
+                    println!("pause stream failed: {e}");
+                }
+                Task::none()
+            }
             LobbyMessage::RpcNotify(v) => match v {
+                Ok(Notification::Chat(v)) => {
+                    self.chat.push(v);
+                    Task::none()
+                }
+                // the lobby may be a new one after a reconnect, so start over
+                Ok(Notification::ChatHistory(v)) => {
+                    self.chat = v;
+                    Task::none()
+                }
                 Ok(v) => {
                     println!("got message: {v:#?}");
+                    if let Notification::LobbyDelta(delta) = &v {
+                        match delta.event {
+                            LobbyEvent::ClientLeft(id) if id == self.server_client.member_id() => {
+                                println!("removed from the lobby by the host");
+                                return Task::done(LobbyMessage::Leave);
+                            }
+                            LobbyEvent::StreamChanged {
+                                id,
+                                state: StreamState::Ended(StreamEndReason::StoppedByHost),
+                            } if id == self.server_client.member_id() => {
+                                println!("stream stopped by the host");
+                                self.error = Some("The host stopped your stream".to_string());
+                                self.my_stream = None;
+                            }
+                            _ => {}
+                        }
+                    }
                     let mut tasks = Vec::new();
-                    smol::block_on(async { self.handle_lobby_info(v, &mut tasks).await }).unwrap();
+                    let res = smol::block_on(async {
+                        let info = self.update_model(v).await?;
+                        self.handle_lobby_info(info, &mut tasks).await
+                    });
+                    if let Err(e) = res {
+                        // the new connection starts with a fresh snapshot
+                        println!("lobby update failed: {e}");
+                        return self.reconnect();
+                    }
                     Task::batch(tasks)
                 }
                 Err(e) => {
-                    // todo: retry connection
                     println!("rpc notify failed: {e}");
-                    Task::none()
+                    self.reconnect()
                 }
             },
+            LobbyMessage::Reconnected(v) => {
+                let conn = v.and_then(|v| {
+                    v.0.lock()
+                        .unwrap()
+                        .take()
+                        .ok_or_else(|| "connection was already used".to_string())
+                });
+                let conn = match conn {
+                    Ok(v) => v,
+                    Err(e) => {
+                        println!("reconnect failed: {e}");
+                        return Task::none();
+                    }
+                };
+                match smol::block_on(self.use_connection(conn)) {
+                    Ok(task) => task,
+                    Err(e) => {
+                        println!("rejoining failed: {e}");
+                        Task::none()
+                    }
+                }
+            }
+            LobbyMessage::NewPasswordChanged(v) => {
+                self.new_password = v;
+                Task::none()
+            }
+            LobbyMessage::SetPassword => {
+                let password = (!self.new_password.is_empty()).then(|| self.new_password.clone());
+                let access = LobbyAccessData {
+                    password: password.clone(),
+                    invite_only: false,
+                };
+                match smol::block_on(self.server_client.rpc.set_lobby_access(access)) {
+                    // used for our own rejoin after a reconnect
+                    Ok(_) => self.credentials.password = password,
+                    Err(e) => println!("set password failed: {e}"),
+                }
+                Task::none()
+            }
+            LobbyMessage::CreateInvite => {
+                let data = CreateInviteData {
+                    ttl_secs: INVITE_TTL_SECS,
+                };
+                match smol::block_on(self.server_client.rpc.create_invite(data)) {
+                    Ok(v) => {
+                        println!("invite: {}", v.token);
+                        self.invite = Some(v.token);
+                    }
+                    Err(e) => println!("create invite failed: {e}"),
+                }
+                Task::none()
+            }
+            LobbyMessage::Kick(id) => {
+                if let Err(e) = smol::block_on(self.server_client.rpc.kick(TargetData { id })) {
+                    println!("kick failed: {e}");
+                }
+                Task::none()
+            }
+            LobbyMessage::Ban(id) => {
+                if let Err(e) = smol::block_on(self.server_client.rpc.ban(TargetData { id })) {
+                    println!("ban failed: {e}");
+                }
+                Task::none()
+            }
+            LobbyMessage::StopClientStream(id) => {
+                let data = TargetData { id };
+                if let Err(e) = smol::block_on(self.server_client.rpc.stop_client_stream(data)) {
+                    println!("stop stream failed: {e}");
+                }
+                Task::none()
+            }
+            LobbyMessage::TransferHost(id) => {
+                if let Err(e) =
+                    smol::block_on(self.server_client.rpc.transfer_host(TargetData { id }))
+                {
+                    println!("transfer host failed: {e}");
+                }
+                Task::none()
+            }
+            LobbyMessage::ChatInputChanged(v) => {
+                self.chat_input = v;
+                Task::none()
+            }
+            LobbyMessage::StreamTitleChanged(v) => {
+                self.stream_title = v;
+                Task::none()
+            }
+            LobbyMessage::Watch(id) => {
+                self.stop_watching();
+                match smol::block_on(self.server_client.rpc.subscribe(TargetData { id })) {
+                    Ok(_) => self.watching = Some(id),
+                    Err(e) => println!("watch failed: {e}"),
+                }
+                // the peer stream starts with the next lobby update
+                Task::none()
+            }
+            LobbyMessage::StopWatching => {
+                self.stop_watching();
+                Task::none()
+            }
+            LobbyMessage::CheckPeerTraffic => {
+                let dark = self.peer_stream.as_ref().is_some_and(|v| !v.receiving);
+                if !dark || self.server_client.relay.is_some() {
+                    return Task::none();
+                }
+                println!("no video on the direct path, falling back to the relay");
+                if let Err(e) = smol::block_on(self.server_client.allocate_relay()) {
+                    println!("relay allocation failed: {e}");
+                    return Task::none();
+                }
+                let keepalive = Task::stream(smol::Timer::interval(RELAY_KEEPALIVE))
+                    .map(|_| LobbyMessage::RelayKeepalive);
+                self.guard(keepalive)
+            }
+            LobbyMessage::RelayKeepalive => {
+                if let Err(e) = smol::block_on(self.server_client.keep_relay()) {
+                    println!("relay keepalive failed: {e}");
+                }
+                Task::none()
+            }
+            LobbyMessage::SendChat => {
+                let data = SendChatData {
+                    text: std::mem::take(&mut self.chat_input),
+                };
+                if let Err(e) = smol::block_on(self.server_client.rpc.send_chat(data)) {
+                    println!("send chat failed: {e}");
+                }
+                Task::none()
+            }
             LobbyMessage::Leave => unreachable!("should be handled above"),
         }
     }
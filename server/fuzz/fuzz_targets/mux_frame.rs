@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use server::mux;
+
+fuzz_target!(|data: &[u8]| {
+    smol::block_on(async {
+        let mut frames = data;
+        while let Ok((_, frame)) = mux::read_frame(&mut frames, 4096).await {
+            assert!(frame.len() <= 4096);
+        }
+    });
+});
@@ -0,0 +1,40 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use server::{
+    codec::Codec,
+    rpc::{JoinLobbyData, RpcCode, RpcNotifyCode, RpcReader},
+    state::{ChatMessage, LobbyDelta, LobbyInfoData},
+};
+
+fuzz_target!(|data: &[u8]| {
+    smol::block_on(async {
+        for codec in [Codec::Json, Codec::MessagePack] {
+            let mut reader = RpcReader::new(data, codec).with_max_frame_size(4096);
+            while let Ok(request) = reader.recv_request::<RpcCode>().await {
+                if let (RpcCode::JoinLobby, Ok(data)) = (request.code, request.data) {
+                    let _ = codec.decode::<JoinLobbyData>(&data);
+                }
+            }
+
+            let mut reader = RpcReader::new(data, codec).with_max_frame_size(4096);
+            while let Ok(request) = reader.recv_request::<RpcNotifyCode>().await {
+                match (request.code, request.data) {
+                    (RpcNotifyCode::LobbyInfo, Ok(data)) => {
+                        let _ = codec.decode::<LobbyInfoData>(&data);
+                    }
+                    (RpcNotifyCode::LobbyDelta, Ok(data)) => {
+                        let _ = codec.decode::<LobbyDelta>(&data);
+                    }
+                    (RpcNotifyCode::Chat, Ok(data)) => {
+                        let _ = codec.decode::<ChatMessage>(&data);
+                    }
+                    (RpcNotifyCode::ChatHistory, Ok(data)) => {
+                        let _ = codec.decode::<Vec<ChatMessage>>(&data);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    });
+});
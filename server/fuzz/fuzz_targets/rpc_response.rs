@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use server::{codec::Codec, rpc::RpcReader};
+
+fuzz_target!(|data: &[u8]| {
+    smol::block_on(async {
+        for codec in [Codec::Json, Codec::MessagePack] {
+            let mut reader = RpcReader::new(data, codec).with_max_frame_size(4096);
+            while reader.recv_response().await.is_ok() {}
+        }
+    });
+});
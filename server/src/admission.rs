@@ -0,0 +1,271 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time,
+};
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdmissionConfig {
+    /// How long an accepted connection may take to finish the handshake
+    #[serde(
+        rename = "pending_ttl_secs",
+        deserialize_with = "crate::config::duration_secs"
+    )]
+    pub pending_ttl: time::Duration,
+    pub max_pending: usize,
+    pub max_pending_per_ip: usize,
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    pub max_lobbies: usize,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self {
+            pending_ttl: time::Duration::from_secs(5),
+            max_pending: 256,
+            max_pending_per_ip: 8,
+            max_connections: 1024,
+            max_connections_per_ip: 16,
+            max_lobbies: 256,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    TooManyPending,
+    TooManyPendingFromIp,
+    TooManyConnections,
+    TooManyConnectionsFromIp,
+    HandshakeTimeout,
+    InvalidHandshake,
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Self::TooManyPending => "server is busy accepting other connections",
+            Self::TooManyPendingFromIp => "too many pending connections from your address",
+            Self::TooManyConnections => "server is full",
+            Self::TooManyConnectionsFromIp => "too many connections from your address",
+            Self::HandshakeTimeout => "handshake timed out",
+            Self::InvalidHandshake => "invalid handshake",
+            Self::UnsupportedVersion(version) => {
+                return write!(
+                    f,
+                    "unsupported protocol version {}, server speaks {} to {}",
+                    version,
+                    crate::conn::MIN_PROTOCOL_VERSION,
+                    crate::conn::PROTOCOL_VERSION
+                );
+            }
+        };
+        f.write_str(reason)
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// Which limit a connection ran into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Limit {
+    Total,
+    PerIp,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl Counts {
+    fn check(&self, ip: IpAddr, max: usize, max_per_ip: usize) -> Result<(), Limit> {
+        if self.total >= max {
+            return Err(Limit::Total);
+        }
+        if self.per_ip.get(&ip).copied().unwrap_or_default() >= max_per_ip {
+            return Err(Limit::PerIp);
+        }
+        Ok(())
+    }
+
+    fn acquire(&mut self, ip: IpAddr, max: usize, max_per_ip: usize) -> Result<(), Limit> {
+        self.check(ip, max, max_per_ip)?;
+        *self.per_ip.entry(ip).or_default() += 1;
+        self.total += 1;
+        Ok(())
+    }
+
+    fn release(&mut self, ip: IpAddr) {
+        self.total -= 1;
+        if let Some(per_ip) = self.per_ip.get_mut(&ip) {
+            *per_ip -= 1;
+            if *per_ip == 0 {
+                self.per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+/// Keeps an admitted connection counted until dropped
+#[derive(Debug)]
+pub struct Permit {
+    counts: Arc<Mutex<Counts>>,
+    ip: IpAddr,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.counts.lock().unwrap().release(self.ip);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Admission {
+    config: AdmissionConfig,
+    pending: Arc<Mutex<Counts>>,
+    live: Arc<Mutex<Counts>>,
+}
+
+impl Admission {
+    pub fn new(config: AdmissionConfig) -> Self {
+        Self {
+            config,
+            pending: Arc::new(Mutex::new(Counts::default())),
+            live: Arc::new(Mutex::new(Counts::default())),
+        }
+    }
+
+    pub fn config(&self) -> &AdmissionConfig {
+        &self.config
+    }
+
+    fn live_rejection(limit: Limit) -> Rejection {
+        match limit {
+            Limit::Total => Rejection::TooManyConnections,
+            Limit::PerIp => Rejection::TooManyConnectionsFromIp,
+        }
+    }
+
+    /// Admits a freshly accepted connection into the handshake phase
+    pub fn admit_pending(&self, ip: IpAddr) -> Result<Permit, Rejection> {
+        // no point in handshaking if the connection can't go live anyway
+        self.live
+            .lock()
+            .unwrap()
+            .check(
+                ip,
+                self.config.max_connections,
+                self.config.max_connections_per_ip,
+            )
+            .map_err(Self::live_rejection)?;
+
+        self.pending
+            .lock()
+            .unwrap()
+            .acquire(ip, self.config.max_pending, self.config.max_pending_per_ip)
+            .map_err(|limit| match limit {
+                Limit::Total => Rejection::TooManyPending,
+                Limit::PerIp => Rejection::TooManyPendingFromIp,
+            })?;
+
+        Ok(Permit {
+            counts: self.pending.clone(),
+            ip,
+        })
+    }
+
+    /// Admits a connection which finished the handshake
+    pub fn admit_live(&self, ip: IpAddr) -> Result<Permit, Rejection> {
+        self.live
+            .lock()
+            .unwrap()
+            .acquire(
+                ip,
+                self.config.max_connections,
+                self.config.max_connections_per_ip,
+            )
+            .map_err(Self::live_rejection)?;
+
+        Ok(Permit {
+            counts: self.live.clone(),
+            ip,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(n: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, n])
+    }
+
+    fn admission() -> Admission {
+        Admission::new(AdmissionConfig {
+            max_pending: 3,
+            max_pending_per_ip: 2,
+            max_connections: 3,
+            max_connections_per_ip: 2,
+            ..AdmissionConfig::default()
+        })
+    }
+
+    #[test]
+    fn live_rejection_names_the_limit() {
+        let admission = admission();
+        let _a = admission.admit_live(ip(1)).unwrap();
+        let _b = admission.admit_live(ip(1)).unwrap();
+        assert_eq!(
+            admission.admit_live(ip(1)).unwrap_err(),
+            Rejection::TooManyConnectionsFromIp
+        );
+
+        let _c = admission.admit_live(ip(2)).unwrap();
+        assert_eq!(
+            admission.admit_live(ip(3)).unwrap_err(),
+            Rejection::TooManyConnections
+        );
+        assert_eq!(
+            admission.admit_pending(ip(3)).unwrap_err(),
+            Rejection::TooManyConnections
+        );
+    }
+
+    #[test]
+    fn pending_rejection_names_the_limit() {
+        let admission = admission();
+        let _a = admission.admit_pending(ip(1)).unwrap();
+        let _b = admission.admit_pending(ip(1)).unwrap();
+        assert_eq!(
+            admission.admit_pending(ip(1)).unwrap_err(),
+            Rejection::TooManyPendingFromIp
+        );
+
+        let _c = admission.admit_pending(ip(2)).unwrap();
+        assert_eq!(
+            admission.admit_pending(ip(3)).unwrap_err(),
+            Rejection::TooManyPending
+        );
+    }
+
+    #[test]
+    fn dropped_permit_frees_the_slot() {
+        let admission = admission();
+        let a = admission.admit_live(ip(1)).unwrap();
+        let _b = admission.admit_live(ip(1)).unwrap();
+        assert!(admission.admit_live(ip(1)).is_err());
+
+        drop(a);
+        let _c = admission.admit_live(ip(1)).unwrap();
+    }
+}
@@ -0,0 +1,155 @@
+use std::{io, time};
+
+use ring::hmac;
+
+use crate::conn::rand_bytes;
+
+/// Expiry as unix seconds followed by the sha256 tag
+const INVITE_LEN: usize = 8 + 32;
+
+fn random_key() -> io::Result<hmac::Key> {
+    let mut key = [0; 32];
+    rand_bytes(&mut key)?;
+    Ok(hmac::Key::new(hmac::HMAC_SHA256, &key))
+}
+
+/// Keyed hash of a lobby password, the password itself is never kept.
+///
+/// Lobbies only live in memory, so this guards against leaking the password
+/// rather than offline cracking, and stays cheap to check on every join
+#[derive(Debug, Clone)]
+pub struct PasswordHash {
+    key: hmac::Key,
+    tag: hmac::Tag,
+}
+
+impl PasswordHash {
+    pub fn new(password: &str) -> io::Result<Self> {
+        let key = random_key()?;
+        let tag = hmac::sign(&key, password.as_bytes());
+        Ok(Self { key, tag })
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        hmac::verify(&self.key, password.as_bytes(), self.tag.as_ref()).is_ok()
+    }
+}
+
+/// Signs invite tokens for one lobby, so tokens stop working
+/// once the lobby is gone even if its id is reused
+#[derive(Debug, Clone)]
+pub struct InviteKey {
+    key: hmac::Key,
+}
+
+fn unix_secs(time: time::SystemTime) -> u64 {
+    time.duration_since(time::UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or(0)
+}
+
+impl InviteKey {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self { key: random_key()? })
+    }
+
+    /// Hex encoded token that lets its holder join `lobby_id` until `expires_at`
+    pub fn issue(&self, lobby_id: &str, expires_at: time::SystemTime) -> String {
+        let expires_at = unix_secs(expires_at).to_le_bytes();
+        let tag = hmac::sign(&self.key, &Self::message(lobby_id, &expires_at));
+
+        expires_at
+            .iter()
+            .chain(tag.as_ref())
+            .map(|v| format!("{:02x}", v))
+            .collect()
+    }
+
+    pub fn verify(&self, lobby_id: &str, token: &str) -> bool {
+        let Some(token) = decode_hex(token) else {
+            return false;
+        };
+        if token.len() != INVITE_LEN {
+            return false;
+        }
+        let (expires_at, tag) = token.split_at(8);
+        let expires_at: [u8; 8] = expires_at.try_into().unwrap();
+        if u64::from_le_bytes(expires_at) < unix_secs(time::SystemTime::now()) {
+            return false;
+        }
+        hmac::verify(&self.key, &Self::message(lobby_id, &expires_at), tag).is_ok()
+    }
+
+    fn message(lobby_id: &str, expires_at: &[u8; 8]) -> Vec<u8> {
+        [expires_at, lobby_id.as_bytes()].concat()
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    // from_str_radix would also take a sign, and slicing needs char boundaries
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|v| v.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOBBY: &str = "sunny-otter-3fa9c2d1";
+
+    fn in_an_hour() -> time::SystemTime {
+        time::SystemTime::now() + time::Duration::from_secs(60 * 60)
+    }
+
+    #[test]
+    fn invite_lets_in_until_it_expires() {
+        let key = InviteKey::new().unwrap();
+        assert!(key.verify(LOBBY, &key.issue(LOBBY, in_an_hour())));
+
+        let expired = time::SystemTime::now() - time::Duration::from_secs(60);
+        assert!(!key.verify(LOBBY, &key.issue(LOBBY, expired)));
+    }
+
+    #[test]
+    fn invite_is_bound_to_its_lobby() {
+        let key = InviteKey::new().unwrap();
+        let token = key.issue(LOBBY, in_an_hour());
+        assert!(!key.verify("sunny-otter-3fa9c2d2", &token));
+
+        // a lobby that got the same id later has its own key
+        let other = InviteKey::new().unwrap();
+        assert!(!other.verify(LOBBY, &token));
+    }
+
+    #[test]
+    fn tampered_invite_is_refused() {
+        let key = InviteKey::new().unwrap();
+        let token = key.issue(LOBBY, in_an_hour());
+
+        // pushing the expiry back breaks the tag
+        let mut later = token.clone().into_bytes();
+        later[2] = if later[2] == b'f' { b'e' } else { b'f' };
+        assert!(!key.verify(LOBBY, std::str::from_utf8(&later).unwrap()));
+
+        // the top byte of the expiry is zero, "+0" would parse to the same value
+        assert_eq!(&token[14..16], "00");
+        let signed = format!("{}+0{}", &token[..14], &token[16..]);
+        assert!(!key.verify(LOBBY, &signed));
+
+        assert!(!key.verify(LOBBY, &token[..token.len() - 2]));
+        assert!(!key.verify(LOBBY, ""));
+    }
+
+    #[test]
+    fn password_hash_checks_the_password() {
+        let hash = PasswordHash::new("hunter2").unwrap();
+        assert!(hash.verify("hunter2"));
+        assert!(!hash.verify("hunter3"));
+        assert!(!hash.verify(""));
+    }
+}
@@ -0,0 +1,37 @@
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::conn::{Features, Hello};
+
+/// How rpc payloads are encoded on a connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// Readable, kept around for debugging
+    #[default]
+    Json,
+    /// MessagePack with named fields, used when both sides support it
+    MessagePack,
+}
+
+impl Codec {
+    pub fn negotiated(hello: &Hello) -> Self {
+        if hello.features.contains(Features::MSGPACK) {
+            Self::MessagePack
+        } else {
+            Self::Json
+        }
+    }
+
+    pub fn encode<S: Serialize>(self, value: &S) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            Self::Json => serde_json::to_vec(value)?,
+            Self::MessagePack => rmp_serde::to_vec_named(value)?,
+        })
+    }
+
+    pub fn decode<D: DeserializeOwned>(self, data: &[u8]) -> anyhow::Result<D> {
+        Ok(match self {
+            Self::Json => serde_json::from_slice(data)?,
+            Self::MessagePack => rmp_serde::from_slice(data)?,
+        })
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time,
+};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::{admission::AdmissionConfig, mux, relay::RelayConfig, rpc, state::LobbyLimits};
+
+pub(crate) fn duration_secs<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<time::Duration, D::Error> {
+    Ok(time::Duration::from_secs(u64::deserialize(deserializer)?))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Server configuration, read from a toml file.
+/// Every field is optional and falls back to [`Config::default`]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log_level: String,
+    pub tcp_bind: String,
+    pub tcp_port: u16,
+    pub udp_bind: String,
+    pub udp_port: u16,
+    /// Never negotiate MessagePack, so rpc payloads stay readable
+    pub json_rpc: bool,
+    /// Largest frame accepted from clients, in bytes
+    pub max_frame_size: usize,
+    /// How long a disconnected client keeps its session and lobby spot
+    #[serde(rename = "session_grace_secs", deserialize_with = "duration_secs")]
+    pub session_grace: time::Duration,
+    /// How often clients are pinged when they support heartbeats
+    #[serde(rename = "heartbeat_interval_secs", deserialize_with = "duration_secs")]
+    pub heartbeat_interval: time::Duration,
+    /// How long a silent client is kept before its connection is closed
+    #[serde(rename = "heartbeat_timeout_secs", deserialize_with = "duration_secs")]
+    pub heartbeat_timeout: time::Duration,
+    pub tls: Option<TlsConfig>,
+    pub admission: AdmissionConfig,
+    pub lobby_limits: LobbyLimits,
+    pub relay: RelayConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            log_level: "info".to_string(),
+            tcp_bind: "127.0.0.1".to_string(),
+            tcp_port: 3000,
+            udp_bind: "127.0.0.1".to_string(),
+            udp_port: 4000,
+            json_rpc: false,
+            max_frame_size: mux::DEFAULT_MAX_FRAME_SIZE,
+            session_grace: time::Duration::from_secs(30),
+            heartbeat_interval: rpc::Heartbeat::default().interval,
+            heartbeat_timeout: rpc::Heartbeat::default().timeout,
+            tls: None,
+            admission: AdmissionConfig::default(),
+            lobby_limits: LobbyLimits::default(),
+            relay: RelayConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        toml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("invalid config {}: {}", path.display(), e))
+    }
+
+    pub fn heartbeat(&self) -> rpc::Heartbeat {
+        rpc::Heartbeat {
+            interval: self.heartbeat_interval,
+            timeout: self.heartbeat_timeout,
+        }
+    }
+}
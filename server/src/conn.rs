@@ -1,116 +1,544 @@
 use std::{
-    collections::HashMap,
     fs,
     io::{self, prelude::*},
+    net::SocketAddr,
+    time,
 };
 
 use futures::{channel::mpsc, prelude::*};
+use futures_rustls::TlsAcceptor;
 use smol::net::{TcpListener, TcpStream};
 
-fn rand_bytes(buf: &mut [u8]) -> io::Result<()> {
+use crate::{
+    admission::{Admission, Rejection},
+    mux::{self, MuxChannel},
+    rpc::Heartbeat,
+    session::{ResumeToken, Sessions},
+    tls::{TlsClient, Trust},
+};
+
+pub(crate) fn rand_bytes(buf: &mut [u8]) -> io::Result<()> {
     let mut dev_random = fs::File::open("/dev/random")?;
     dev_random.read_exact(buf)
 }
 
 pub type TcpId = [u8; 32];
 
-pub type TcpSenderReceiver = (TcpId, TcpStream, TcpStream);
+/// Protocol version spoken by this build, bumped on incompatible changes
+pub const PROTOCOL_VERSION: u32 = 4;
+/// Oldest client version the server still accepts
+pub const MIN_PROTOCOL_VERSION: u32 = 4;
+
+/// Optional protocol features, only used when both sides support them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Features(u32);
+
+impl Features {
+    pub const NONE: Self = Self(0);
+    /// MessagePack rpc payloads instead of json
+    pub const MSGPACK: Self = Self(1 << 0);
+    /// Ping frames on both channels so a dead peer gets noticed
+    pub const HEARTBEAT: Self = Self(1 << 1);
+    /// Every feature this build knows about
+    pub const SUPPORTED: Self = Self(Self::MSGPACK.0 | Self::HEARTBEAT.0);
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+/// What both sides agreed on during the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u32,
+    pub features: Features,
+}
+
+#[derive(Debug)]
+pub struct TcpSenderReceiver {
+    pub id: TcpId,
+    /// The other end, where the client connected from on the server
+    pub addr: SocketAddr,
+    pub hello: Hello,
+    /// The server's ping timing, set when both sides support heartbeats
+    pub heartbeat: Option<Heartbeat>,
+    /// Lets the client take over this session after a reconnect
+    pub resume_token: ResumeToken,
+    /// Whether an existing session was resumed instead of a new one started
+    pub resumed: bool,
+    pub sender: MuxChannel,
+    pub receiver: MuxChannel,
+}
+
+/// Sent by the client right after connecting,
+/// followed by its [`Hello`] and the session it wants to resume
+const HANDSHAKE_MAGIC: [u8; 4] = *b"BSS\0";
+
+const HANDSHAKE_NEW_SESSION: u8 = 0;
+const HANDSHAKE_RESUME_SESSION: u8 = 1;
+
+const HANDSHAKE_ACCEPTED: u8 = 0;
+const HANDSHAKE_REJECTED: u8 = 1;
+
+const MAX_REJECTION_LEN: u32 = 1024;
+
+/// Plain or tls wrapped tcp stream
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T> Stream for T where T: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+type BoxedStream = Box<dyn Stream>;
+
+async fn read_u32(stream: &mut BoxedStream) -> io::Result<u32> {
+    let mut v = [0; 4];
+    stream.read_exact(&mut v).await?;
+    Ok(u32::from_le_bytes(v))
+}
+
+async fn write_hello(stream: &mut BoxedStream, hello: &Hello) -> io::Result<()> {
+    stream.write_all(&hello.version.to_le_bytes()).await?;
+    stream.write_all(&hello.features.bits().to_le_bytes()).await
+}
+
+async fn read_hello(stream: &mut BoxedStream) -> io::Result<Hello> {
+    Ok(Hello {
+        version: read_u32(stream).await?,
+        features: Features::from_bits(read_u32(stream).await?),
+    })
+}
+
+/// Reads the client hello and returns what the connection will use,
+/// along with the session the client wants to resume
+async fn read_handshake(
+    stream: &mut BoxedStream,
+    features: Features,
+) -> Result<(Hello, Option<(TcpId, ResumeToken)>), Rejection> {
+    let mut magic = [0; 4];
+    stream
+        .read_exact(&mut magic)
+        .await
+        .map_err(|_| Rejection::InvalidHandshake)?;
+    if magic != HANDSHAKE_MAGIC {
+        return Err(Rejection::InvalidHandshake);
+    }
+
+    let client = read_hello(stream)
+        .await
+        .map_err(|_| Rejection::InvalidHandshake)?;
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&client.version) {
+        return Err(Rejection::UnsupportedVersion(client.version));
+    }
+    let hello = Hello {
+        version: client.version,
+        features: client.features.intersection(features),
+    };
+
+    let resume = read_resume(stream)
+        .await
+        .map_err(|_| Rejection::InvalidHandshake)?;
+    Ok((hello, resume))
+}
+
+async fn read_resume(stream: &mut BoxedStream) -> io::Result<Option<(TcpId, ResumeToken)>> {
+    let mut kind = [0; 1];
+    stream.read_exact(&mut kind).await?;
+    match kind[0] {
+        HANDSHAKE_NEW_SESSION => Ok(None),
+        HANDSHAKE_RESUME_SESSION => {
+            let mut id: TcpId = [0; 32];
+            stream.read_exact(&mut id).await?;
+            let mut token: ResumeToken = [0; 32];
+            stream.read_exact(&mut token).await?;
+            Ok(Some((id, token)))
+        }
+        _ => Err(io::ErrorKind::InvalidData.into()),
+    }
+}
+
+async fn write_accepted(
+    stream: &mut BoxedStream,
+    id: &TcpId,
+    token: &ResumeToken,
+    resumed: bool,
+    hello: &Hello,
+    heartbeat: Option<&Heartbeat>,
+) -> io::Result<()> {
+    stream.write_all(&[HANDSHAKE_ACCEPTED]).await?;
+    stream.write_all(id).await?;
+    stream.write_all(token).await?;
+    stream.write_all(&[resumed as u8]).await?;
+    write_hello(stream, hello).await?;
+    if let Some(heartbeat) = heartbeat {
+        write_millis(stream, heartbeat.interval).await?;
+        write_millis(stream, heartbeat.timeout).await?;
+    }
+    stream.flush().await
+}
+
+async fn write_millis(stream: &mut BoxedStream, duration: time::Duration) -> io::Result<()> {
+    let millis = u32::try_from(duration.as_millis()).unwrap_or(u32::MAX);
+    stream.write_all(&millis.to_le_bytes()).await
+}
+
+async fn read_millis(stream: &mut BoxedStream) -> io::Result<time::Duration> {
+    Ok(time::Duration::from_millis(read_u32(stream).await?.into()))
+}
+
+async fn write_rejected(stream: &mut BoxedStream, rejection: &Rejection) -> io::Result<()> {
+    let reason = rejection.to_string();
+    stream.write_all(&[HANDSHAKE_REJECTED]).await?;
+    stream
+        .write_all(&(reason.len() as u32).to_le_bytes())
+        .await?;
+    stream.write_all(reason.as_bytes()).await?;
+    stream.close().await
+}
+
+async fn before_deadline<T>(
+    deadline: time::Instant,
+    fut: impl Future<Output = Result<T, Rejection>>,
+) -> Result<T, Rejection> {
+    smol::future::or(fut, async {
+        smol::Timer::at(deadline).await;
+        Err(Rejection::HandshakeTimeout)
+    })
+    .await
+}
 
 pub struct TcpSendReceive {
-    sub_listener: TcpListener,
-    rpc_listener: TcpListener,
-    id_map: crate::ArcMu<HashMap<TcpId, TcpStream>>,
+    listener: TcpListener,
+    admission: Admission,
+    sessions: Sessions,
+    tls: Option<TlsAcceptor>,
+    features: Features,
+    heartbeat: Heartbeat,
+    max_frame_size: usize,
     accept_tx: mpsc::Sender<TcpSenderReceiver>,
 }
 
 impl TcpSendReceive {
     pub async fn new(
         host: &str,
-        port: usize,
+        port: u16,
+        admission: Admission,
+        sessions: Sessions,
         accept_tx: mpsc::Sender<TcpSenderReceiver>,
     ) -> anyhow::Result<Self> {
-        let sub_listener = TcpListener::bind(format!("{}:{}", host, port)).await?;
-        let rpc_listener = TcpListener::bind(format!("{}:{}", host, port + 1)).await?;
+        let listener = TcpListener::bind((host, port)).await?;
 
         Ok(Self {
             accept_tx,
-            sub_listener,
-            rpc_listener,
-            id_map: crate::arcmu(HashMap::new()),
+            admission,
+            sessions,
+            tls: None,
+            features: Features::SUPPORTED,
+            heartbeat: Heartbeat::default(),
+            max_frame_size: mux::DEFAULT_MAX_FRAME_SIZE,
+            listener,
+        })
+    }
+
+    pub fn with_tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// Where the listener ended up, for binding to port `0`
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Limits the optional features offered to clients
+    pub fn with_features(mut self, features: Features) -> Self {
+        self.features = features.intersection(Features::SUPPORTED);
+        self
+    }
+
+    /// Ping timing sent to clients, which use it for their side of the connection
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    async fn upgrade(
+        stream: TcpStream,
+        tls: Option<TlsAcceptor>,
+    ) -> Result<BoxedStream, Rejection> {
+        Ok(match tls {
+            Some(acceptor) => Box::new(
+                acceptor
+                    .accept(stream)
+                    .await
+                    .map_err(|_| Rejection::InvalidHandshake)?,
+            ),
+            None => Box::new(stream),
         })
     }
 
     pub async fn listen(self) -> anyhow::Result<()> {
-        let sub_listener = async {
-            loop {
-                let (mut stream, _) = self.sub_listener.accept().await?;
-                let mut id: TcpId = [0; 32];
-                rand_bytes(&mut id)?;
-                stream.write_all(&id).await?;
-                self.id_map.lock().await.insert(id, stream);
-            }
-        };
+        loop {
+            let (stream, addr) = self.listener.accept().await?;
+            let admission = self.admission.clone();
+            let sessions = self.sessions.clone();
+            let tls = self.tls.clone();
+            let features = self.features;
+            let heartbeat = self.heartbeat;
+            let max_frame_size = self.max_frame_size;
+            let mut accept_tx = self.accept_tx.clone();
+            smol::spawn::<anyhow::Result<()>>(async move {
+                let deadline = time::Instant::now() + admission.config().pending_ttl;
 
-        let rpc_listener = async {
-            loop {
-                let (mut stream, _) = self.rpc_listener.accept().await?;
-                let id_map = self.id_map.clone();
-                let mut accept_tx = self.accept_tx.clone();
-                smol::spawn::<anyhow::Result<()>>(async move {
-                    let mut id: TcpId = [0; 32];
-                    stream.read_exact(&mut id).await?;
-
-                    let mut id_map = id_map.lock().await;
-                    if !id_map.contains_key(&id) {
+                // held until the connection goes live or is rejected
+                let _pending = match admission.admit_pending(addr.ip()) {
+                    Ok(v) => v,
+                    // not worth a tls handshake, only plain connections get the reason
+                    Err(rejection) => {
+                        log::info!("rejected {addr}: {rejection}");
+                        if tls.is_none() {
+                            let mut stream: BoxedStream = Box::new(stream);
+                            write_rejected(&mut stream, &rejection).await?;
+                        }
+                        return Ok(());
+                    }
+                };
+                let mut stream = match before_deadline(deadline, Self::upgrade(stream, tls)).await {
+                    Ok(v) => v,
+                    Err(rejection) => {
+                        log::info!("dropped {addr}: {rejection}");
                         return Ok(());
                     }
-                    let sender = id_map
-                        .remove(&id)
-                        .ok_or_else(|| anyhow::anyhow!("expected id to be in map"))?;
+                };
 
-                    stream.write_all(b"ok").await?;
-                    accept_tx.send((id, sender, stream)).await?;
+                let admitted = before_deadline(deadline, read_handshake(&mut stream, features))
+                    .await
+                    .and_then(|(hello, resume)| {
+                        Ok((hello, resume, admission.admit_live(addr.ip())?))
+                    });
+                let (hello, resume, permit) = match admitted {
+                    Ok(v) => v,
+                    Err(rejection) => {
+                        log::info!("rejected {addr}: {rejection}");
+                        write_rejected(&mut stream, &rejection).await?;
+                        return Ok(());
+                    }
+                };
 
-                    Ok(())
-                })
-                .detach();
-            }
-        };
+                let (id, resume_token, resumed) = match resume {
+                    Some((id, token)) if sessions.resume(&id, &token) => (id, token, true),
+                    // expired or unknown sessions start over
+                    _ => {
+                        let (id, token) = sessions.create()?;
+                        (id, token, false)
+                    }
+                };
+                let heartbeat = hello
+                    .features
+                    .contains(Features::HEARTBEAT)
+                    .then_some(heartbeat);
+                write_accepted(
+                    &mut stream,
+                    &id,
+                    &resume_token,
+                    resumed,
+                    &hello,
+                    heartbeat.as_ref(),
+                )
+                .await?;
 
-        let (res1, res2): (anyhow::Result<()>, anyhow::Result<()>) =
-            futures::join!(sub_listener, rpc_listener);
-        res1?;
-        res2?;
+                let (sender, receiver) = mux::mux_guarded(stream, permit, max_frame_size);
+                accept_tx
+                    .send(TcpSenderReceiver {
+                        id,
+                        addr,
+                        hello,
+                        heartbeat,
+                        resume_token,
+                        resumed,
+                        sender,
+                        receiver,
+                    })
+                    .await?;
 
-        Ok(())
+                Ok(())
+            })
+            .detach();
+        }
     }
 }
 
+/// How long the client waits for a connection to be set up unless configured otherwise
+pub const DEFAULT_CLIENT_HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
 pub struct TcpSendReceiveClient {
     host: String,
-    port: usize,
+    port: u16,
+    tls: Option<TlsClient>,
+    features: Features,
+    handshake_timeout: time::Duration,
 }
 
 impl TcpSendReceiveClient {
-    pub fn new(host: String, port: usize) -> Self {
-        Self { host, port }
+    pub fn new(host: String, port: u16) -> Self {
+        Self {
+            host,
+            port,
+            tls: None,
+            features: Features::SUPPORTED,
+            handshake_timeout: DEFAULT_CLIENT_HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    /// Longest the connect, tls and handshake may take together
+    pub fn with_handshake_timeout(mut self, timeout: time::Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
     }
 
+    /// Limits the optional features requested from the server
+    pub fn with_features(mut self, features: Features) -> Self {
+        self.features = features.intersection(Features::SUPPORTED);
+        self
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn with_tls(mut self, trust: Trust) -> anyhow::Result<Self> {
+        self.tls = Some(TlsClient::new(&self.host, self.port, trust)?);
+        Ok(self)
+    }
+
+    /// Connects and starts a new session
     pub async fn create(&self) -> anyhow::Result<TcpSenderReceiver> {
+        self.connect(None).await
+    }
+
+    /// Connects and takes over the session if the server still has it,
+    /// check [`TcpSenderReceiver::resumed`] for whether it did
+    pub async fn resume(
+        &self,
+        id: &TcpId,
+        token: &ResumeToken,
+    ) -> anyhow::Result<TcpSenderReceiver> {
+        self.connect(Some((id, token))).await
+    }
+
+    async fn connect(
+        &self,
+        resume: Option<(&TcpId, &ResumeToken)>,
+    ) -> anyhow::Result<TcpSenderReceiver> {
+        smol::future::or(self.handshake(resume), async {
+            smol::Timer::after(self.handshake_timeout).await;
+            anyhow::bail!("handshake took over {:?}", self.handshake_timeout)
+        })
+        .await
+    }
+
+    async fn handshake(
+        &self,
+        resume: Option<(&TcpId, &ResumeToken)>,
+    ) -> anyhow::Result<TcpSenderReceiver> {
         let mut id: TcpId = [0; 32];
 
-        let mut sender = TcpStream::connect(format!("{}:{}", &self.host, self.port)).await?;
-        let mut receiver = TcpStream::connect(format!("{}:{}", &self.host, self.port + 1)).await?;
+        let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let addr = stream.peer_addr()?;
+        let mut stream: BoxedStream = match &self.tls {
+            Some(tls) => Box::new(tls.connector().connect(tls.server_name(), stream).await?),
+            None => Box::new(stream),
+        };
+        stream.write_all(&HANDSHAKE_MAGIC).await?;
+        write_hello(
+            &mut stream,
+            &Hello {
+                version: PROTOCOL_VERSION,
+                features: self.features,
+            },
+        )
+        .await?;
+        match resume {
+            Some((id, token)) => {
+                stream.write_all(&[HANDSHAKE_RESUME_SESSION]).await?;
+                stream.write_all(id).await?;
+                stream.write_all(token).await?;
+            }
+            None => stream.write_all(&[HANDSHAKE_NEW_SESSION]).await?,
+        }
+        stream.flush().await?;
 
-        sender.read_exact(&mut id).await?;
-        receiver.write_all(&id).await?;
-        let mut ok: [u8; 2] = [0; 2];
-        receiver.read_exact(&mut ok).await?;
+        let mut status = [0; 1];
+        stream.read_exact(&mut status).await?;
+        if status[0] != HANDSHAKE_ACCEPTED {
+            let mut len = [0; 4];
+            stream.read_exact(&mut len).await?;
+            let mut reason = vec![0; u32::from_le_bytes(len).min(MAX_REJECTION_LEN) as _];
+            stream.read_exact(&mut reason).await?;
+            anyhow::bail!(
+                "server rejected connection: {}",
+                String::from_utf8_lossy(&reason)
+            );
+        }
+        stream.read_exact(&mut id).await?;
+        let mut resume_token: ResumeToken = [0; 32];
+        stream.read_exact(&mut resume_token).await?;
+        let mut resumed = [0; 1];
+        stream.read_exact(&mut resumed).await?;
 
-        if ok != *b"ok" {
-            anyhow::bail!("receiver not ok");
+        let hello = read_hello(&mut stream).await?;
+        if hello.version != PROTOCOL_VERSION {
+            anyhow::bail!(
+                "server answered with protocol version {}, expected {}",
+                hello.version,
+                PROTOCOL_VERSION
+            );
+        }
+        if !self.features.contains(hello.features) {
+            anyhow::bail!("server enabled features that were not requested");
         }
+        let heartbeat = if hello.features.contains(Features::HEARTBEAT) {
+            let heartbeat = Heartbeat {
+                interval: read_millis(&mut stream).await?,
+                timeout: read_millis(&mut stream).await?,
+            };
+            if !heartbeat.is_valid() {
+                anyhow::bail!("server sent an unusable heartbeat: {heartbeat:?}");
+            }
+            Some(heartbeat)
+        } else {
+            None
+        };
 
-        Ok((id, sender, receiver))
+        let (sender, receiver) = mux::mux(stream);
+        Ok(TcpSenderReceiver {
+            id,
+            addr,
+            hello,
+            heartbeat,
+            resume_token,
+            resumed: resumed[0] != 0,
+            sender,
+            receiver,
+        })
     }
 }
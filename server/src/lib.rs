@@ -1,9 +1,18 @@
 use smol::lock::Mutex;
 use std::sync::Arc;
 
+pub mod admission;
+pub mod auth;
+pub mod codec;
+pub mod config;
 pub mod conn;
+pub mod mux;
+pub mod relay;
 pub mod rpc;
+mod service;
+pub mod session;
 pub mod state;
+pub mod tls;
 
 pub type ArcMu<T> = Arc<Mutex<T>>;
 
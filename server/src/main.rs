@@ -1,45 +1,206 @@
+use std::{path::PathBuf, time};
+
+use clap::Parser;
 use futures::{channel::mpsc, prelude::*};
 
-use server::{conn, rpc};
+use server::{admission, codec, config, conn, rpc, session, tls};
+
+/// Screenshare lobby server.
+///
+/// Flags take precedence over their environment variables,
+/// which take precedence over the config file
+#[derive(Debug, Parser)]
+struct Cli {
+    /// Toml config file
+    #[arg(short, long, env = "BSS_CONFIG")]
+    config: Option<PathBuf>,
+    #[arg(long, env = "BSS_LOG_LEVEL")]
+    log_level: Option<String>,
+    #[arg(long, env = "BSS_TCP_BIND")]
+    tcp_bind: Option<String>,
+    #[arg(long, env = "BSS_TCP_PORT")]
+    tcp_port: Option<u16>,
+    #[arg(long, env = "BSS_UDP_BIND")]
+    udp_bind: Option<String>,
+    #[arg(long, env = "BSS_UDP_PORT")]
+    udp_port: Option<u16>,
+    /// Keep rpc payloads as json, for debugging
+    #[arg(long, env = "BSS_JSON_RPC")]
+    json_rpc: bool,
+    /// Largest frame accepted from clients, in bytes
+    #[arg(long, env = "BSS_MAX_FRAME_SIZE")]
+    max_frame_size: Option<usize>,
+    /// How long a disconnected client can resume its session
+    #[arg(long, env = "BSS_SESSION_GRACE_SECS")]
+    session_grace_secs: Option<u64>,
+    /// How often clients are pinged
+    #[arg(long, env = "BSS_HEARTBEAT_INTERVAL_SECS")]
+    heartbeat_interval_secs: Option<u64>,
+    /// How long a silent client is kept
+    #[arg(long, env = "BSS_HEARTBEAT_TIMEOUT_SECS")]
+    heartbeat_timeout_secs: Option<u64>,
+    /// Pem certificate chain, enables tls together with `--tls-key`
+    #[arg(long, env = "BSS_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    #[arg(long, env = "BSS_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+}
+
+impl Cli {
+    fn into_config(self) -> anyhow::Result<config::Config> {
+        let mut config = match &self.config {
+            Some(path) => config::Config::from_file(path)?,
+            None => config::Config::default(),
+        };
+
+        if let Some(v) = self.log_level {
+            config.log_level = v;
+        }
+        if let Some(v) = self.tcp_bind {
+            config.tcp_bind = v;
+        }
+        if let Some(v) = self.tcp_port {
+            config.tcp_port = v;
+        }
+        if let Some(v) = self.udp_bind {
+            config.udp_bind = v;
+        }
+        if let Some(v) = self.udp_port {
+            config.udp_port = v;
+        }
+        if self.json_rpc {
+            config.json_rpc = true;
+        }
+        if let Some(v) = self.max_frame_size {
+            config.max_frame_size = v;
+        }
+        if let Some(v) = self.session_grace_secs {
+            config.session_grace = time::Duration::from_secs(v);
+        }
+        if let Some(v) = self.heartbeat_interval_secs {
+            config.heartbeat_interval = time::Duration::from_secs(v);
+        }
+        if let Some(v) = self.heartbeat_timeout_secs {
+            config.heartbeat_timeout = time::Duration::from_secs(v);
+        }
+        if let (Some(cert), Some(key)) = (self.tls_cert, self.tls_key) {
+            config.tls = Some(config::TlsConfig { cert, key });
+        }
+        anyhow::ensure!(
+            config.heartbeat().is_valid(),
+            "heartbeat interval must be above zero and below the timeout"
+        );
+
+        Ok(config)
+    }
+}
+
+fn tls_acceptor(config: &config::TlsConfig) -> anyhow::Result<futures_rustls::TlsAcceptor> {
+    log::info!(
+        "tls certificate fingerprint: {}",
+        tls::fingerprint_to_hex(&tls::cert_fingerprint(&config.cert)?)
+    );
+    tls::acceptor(&config.cert, &config.key)
+}
 
-async fn async_main() -> anyhow::Result<()> {
+async fn async_main(config: config::Config) -> anyhow::Result<()> {
+    let admission = admission::Admission::new(config.admission.clone());
+    let sessions = session::Sessions::new(config.session_grace);
     let (mut notify_tx, notify_rx) = mpsc::channel(8);
 
-    let rpc_server = rpc::RpcServer::new(notify_tx.clone());
+    let rpc_server = rpc::RpcServer::new(
+        notify_tx.clone(),
+        sessions.clone(),
+        rpc::RpcServerConfig {
+            udp_bind: config.udp_bind.clone(),
+            udp_port: config.udp_port,
+            max_lobbies: config.admission.max_lobbies,
+            lobby_limits: config.lobby_limits,
+            relay: config.relay.clone(),
+        },
+    );
     let notifier = rpc::Notifier::new(notify_rx);
 
     let (accept_tx, mut accept_rx) = mpsc::channel(8);
-    let tcp_send_receive = conn::TcpSendReceive::new("127.0.0.1", 3000, accept_tx).await?;
+    let mut tcp_send_receive = conn::TcpSendReceive::new(
+        &config.tcp_bind,
+        config.tcp_port,
+        admission,
+        sessions,
+        accept_tx,
+    )
+    .await?
+    .with_max_frame_size(config.max_frame_size)
+    .with_heartbeat(config.heartbeat());
+    if let Some(tls) = &config.tls {
+        tcp_send_receive = tcp_send_receive.with_tls(tls_acceptor(tls)?);
+    }
+    if config.json_rpc {
+        tcp_send_receive = tcp_send_receive
+            .with_features(conn::Features::SUPPORTED.difference(conn::Features::MSGPACK));
+    }
 
     let handler_fut = async {
         loop {
-            let (tcp_id, sender, receiver) = match accept_rx.recv().await {
+            let conn::TcpSenderReceiver {
+                id: tcp_id,
+                addr,
+                hello,
+                heartbeat,
+                resumed,
+                sender,
+                receiver,
+                ..
+            } = match accept_rx.recv().await {
                 Ok(v) => v,
                 Err(v) => anyhow::bail!(v),
             };
-            println!("accepted conn: {:?}", tcp_id);
+            log::info!(
+                "accepted conn: {:?}, {:?}, resumed: {}",
+                tcp_id,
+                hello,
+                resumed
+            );
+            let rpc_codec = codec::Codec::negotiated(&hello);
+            let new_conn = |channel| {
+                let conn = rpc::RpcConn::new(channel, rpc_codec)
+                    .with_max_frame_size(config.max_frame_size);
+                match heartbeat {
+                    Some(v) => conn.with_heartbeat(v),
+                    None => conn,
+                }
+            };
 
             notify_tx
                 .send(rpc::Notify::NewReceiver(
                     tcp_id,
-                    rpc::RpcNotifyClient::new(receiver.into()),
+                    rpc::RpcNotifyClient::new(new_conn(receiver.clone())),
+                    receiver,
                 ))
                 .await?;
-            let handler = rpc_server.get_handler(tcp_id, sender.into());
+            if resumed {
+                rpc_server.resume(&tcp_id).await?;
+            }
+            let handler = rpc_server.get_handler(tcp_id, addr.ip(), new_conn(sender.clone()));
 
             smol::spawn(async move {
-                match handler.listen().await {
-                    Err(e) => {
-                        println!("handler failed: {}", e);
-                    }
-                    Ok(_) => {}
+                if let Err(e) = handler.listen().await {
+                    log::warn!("handler failed: {}", e);
                 }
+                // the notify channel goes down with the rpc channel
+                sender.shutdown();
             })
             .detach();
         }
     };
 
-    println!("started listening");
+    log::info!(
+        "listening on tcp {}:{}, udp {}:{}",
+        config.tcp_bind,
+        config.tcp_port,
+        config.udp_bind,
+        config.udp_port
+    );
     let (_, _, _, _): ((), (), (), ()) = futures::try_join!(
         tcp_send_receive.listen(),
         rpc_server.listen(),
@@ -51,5 +212,11 @@ async fn async_main() -> anyhow::Result<()> {
 }
 
 fn main() {
-    smol::block_on(async_main()).unwrap();
+    let config = Cli::parse().into_config().unwrap();
+
+    env_logger::Builder::new()
+        .parse_filters(&config.log_level)
+        .init();
+
+    smol::block_on(async_main(config)).unwrap();
 }
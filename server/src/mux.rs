@@ -0,0 +1,289 @@
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, ready},
+};
+
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+};
+
+pub type ChannelId = u32;
+
+pub const RPC_CHANNEL: ChannelId = 0;
+pub const NOTIFY_CHANNEL: ChannelId = 1;
+
+const CHANNEL_BUFFER: usize = 8;
+
+/// Largest frame accepted from the peer unless configured otherwise
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1 << 20;
+
+pub type Frame = (ChannelId, Vec<u8>);
+
+#[derive(Debug)]
+struct MuxChannelReader {
+    frames_rx: mpsc::Receiver<Vec<u8>>,
+    frame: Vec<u8>,
+    pos: usize,
+}
+
+#[derive(Debug)]
+struct MuxChannelWriter {
+    frames_tx: mpsc::Sender<Frame>,
+    buf: Vec<u8>,
+    max_frame_size: usize,
+}
+
+/// One logical stream on top of a multiplexed tcp connection.
+///
+/// Writes are buffered until flush, each flush is sent as a single frame
+/// tagged with the channel id
+#[derive(Debug, Clone)]
+pub struct MuxChannel {
+    id: ChannelId,
+    reader: Arc<Mutex<MuxChannelReader>>,
+    writer: Arc<Mutex<MuxChannelWriter>>,
+    shutdown_tx: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+}
+
+impl MuxChannel {
+    fn new(
+        id: ChannelId,
+        frames_rx: mpsc::Receiver<Vec<u8>>,
+        frames_tx: mpsc::Sender<Frame>,
+        max_frame_size: usize,
+        shutdown_tx: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    ) -> Self {
+        Self {
+            id,
+            shutdown_tx,
+            reader: Arc::new(Mutex::new(MuxChannelReader {
+                frames_rx,
+                frame: vec![],
+                pos: 0,
+            })),
+            writer: Arc::new(Mutex::new(MuxChannelWriter {
+                frames_tx,
+                buf: vec![],
+                max_frame_size,
+            })),
+        }
+    }
+
+    /// Closes the whole connection, both channels read eof afterwards
+    pub fn shutdown(&self) {
+        if let Some(tx) = self.shutdown_tx.lock().unwrap().take() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+impl AsyncRead for MuxChannel {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut reader = self.reader.lock().unwrap();
+        while reader.pos == reader.frame.len() {
+            match ready!(reader.frames_rx.poll_next_unpin(cx)) {
+                Some(frame) => {
+                    reader.frame = frame;
+                    reader.pos = 0;
+                }
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+
+        let len = buf.len().min(reader.frame.len() - reader.pos);
+        buf[..len].copy_from_slice(&reader.frame[reader.pos..reader.pos + len]);
+        reader.pos += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for MuxChannel {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.writer.lock().unwrap().buf.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut writer = self.writer.lock().unwrap();
+        if writer.buf.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        if writer.buf.len() > writer.max_frame_size {
+            writer.buf.clear();
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame too large",
+            )));
+        }
+
+        ready!(writer.frames_tx.poll_ready(cx)).map_err(|_| io::ErrorKind::BrokenPipe)?;
+        let frame = (self.id, std::mem::take(&mut writer.buf));
+        writer
+            .frames_tx
+            .start_send(frame)
+            .map_err(|_| io::ErrorKind::BrokenPipe)?;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// Reads one `[channel][len][data]` frame.
+///
+/// The buffer only grows as data arrives, so a lying length can't make it
+/// allocate more than was actually sent
+pub async fn read_frame(
+    mut reader: impl AsyncRead + Unpin,
+    max_frame_size: usize,
+) -> io::Result<Frame> {
+    let mut channel = [0; 4];
+    reader.read_exact(&mut channel).await?;
+    let mut len = [0; 4];
+    reader.read_exact(&mut len).await?;
+
+    let len = u32::from_le_bytes(len) as usize;
+    if len > max_frame_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {len} bytes is over the limit of {max_frame_size}"),
+        ));
+    }
+
+    let mut data = Vec::new();
+    reader.take(len as _).read_to_end(&mut data).await?;
+    if data.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok((u32::from_le_bytes(channel), data))
+}
+
+/// Hands each frame to its channel without waiting, so a channel that isn't read
+/// can't hold up the other. A peer that gets more than [`CHANNEL_BUFFER`] frames
+/// ahead of a channel's reader is cut off
+async fn demux(
+    mut reader: impl AsyncRead + Unpin,
+    mut channels: HashMap<ChannelId, mpsc::Sender<Vec<u8>>>,
+    max_frame_size: usize,
+) -> io::Result<()> {
+    loop {
+        let (channel, data) = read_frame(&mut reader, max_frame_size).await?;
+        let Some(frames_tx) = channels.get_mut(&channel) else {
+            log::warn!("mux: frame for unknown channel {channel}");
+            continue;
+        };
+        match frames_tx.try_send(data) {
+            Ok(()) => {}
+            Err(e) if e.is_full() => {
+                return Err(io::Error::other(format!(
+                    "channel {channel} is {CHANNEL_BUFFER} frames behind"
+                )));
+            }
+            Err(_) => {
+                channels.remove(&channel);
+            }
+        }
+    }
+}
+
+async fn mux_writer(
+    mut writer: impl AsyncWrite + Unpin,
+    mut frames_rx: mpsc::Receiver<Frame>,
+    closed_rx: oneshot::Receiver<()>,
+) -> io::Result<()> {
+    let mut closed_rx = closed_rx.fuse();
+    loop {
+        let (channel, data) = futures::select! {
+            frame = frames_rx.next() => match frame {
+                Some(v) => v,
+                None => break,
+            },
+            _ = closed_rx => break,
+        };
+        writer.write_all(&channel.to_le_bytes()).await?;
+        writer.write_all(&(data.len() as u32).to_le_bytes()).await?;
+        writer.write_all(&data).await?;
+        writer.flush().await?;
+    }
+    writer.close().await
+}
+
+/// Splits the stream into the rpc and the notify channel.
+///
+/// The connection is shut down once the peer goes away, [`MuxChannel::shutdown`]
+/// is called or every handle to both channels is dropped
+pub fn mux<S>(stream: S) -> (MuxChannel, MuxChannel)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    mux_guarded(stream, (), DEFAULT_MAX_FRAME_SIZE)
+}
+
+/// Same as [`mux`], `guard` is dropped once the connection is closed.
+/// Frames over `max_frame_size` close the connection when read
+/// and fail the flush when written
+pub fn mux_guarded<S, G>(stream: S, guard: G, max_frame_size: usize) -> (MuxChannel, MuxChannel)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    G: Send + 'static,
+{
+    let (frames_tx, frames_rx) = mpsc::channel(CHANNEL_BUFFER);
+    let (rpc_tx, rpc_rx) = mpsc::channel(CHANNEL_BUFFER);
+    let (notify_tx, notify_rx) = mpsc::channel(CHANNEL_BUFFER);
+    let (closed_tx, closed_rx) = oneshot::channel();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let shutdown_tx = Arc::new(Mutex::new(Some(shutdown_tx)));
+
+    let channels = HashMap::from([(RPC_CHANNEL, rpc_tx), (NOTIFY_CHANNEL, notify_tx)]);
+    let (reader, writer) = stream.split();
+
+    smol::spawn(async move {
+        let demux = std::pin::pin!(demux(reader, channels, max_frame_size));
+        match future::select(demux, shutdown_rx).await {
+            future::Either::Left((Err(e), _)) if e.kind() != io::ErrorKind::UnexpectedEof => {
+                log::warn!("mux: read failed: {e}");
+            }
+            _ => {}
+        }
+        drop(closed_tx);
+        drop(guard);
+    })
+    .detach();
+
+    smol::spawn(async move {
+        if let Err(e) = mux_writer(writer, frames_rx, closed_rx).await {
+            log::warn!("mux: write failed: {e}");
+        }
+    })
+    .detach();
+
+    (
+        MuxChannel::new(
+            RPC_CHANNEL,
+            rpc_rx,
+            frames_tx.clone(),
+            max_frame_size,
+            shutdown_tx.clone(),
+        ),
+        MuxChannel::new(
+            NOTIFY_CHANNEL,
+            notify_rx,
+            frames_tx,
+            max_frame_size,
+            shutdown_tx,
+        ),
+    )
+}
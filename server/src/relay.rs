@@ -0,0 +1,318 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time,
+};
+
+use serde::{Deserialize, Serialize};
+use smol::net::UdpSocket;
+
+use crate::conn::{TcpId, rand_bytes};
+
+/// What a client sends to its relay port to claim it, and again to keep it
+pub type RelayToken = [u8; 16];
+
+/// Largest udp payload
+const MAX_PACKET_SIZE: usize = 65536;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayConfig {
+    /// Clients whose direct path doesn't work get nothing without it
+    pub enabled: bool,
+    /// Address the relay ports are bound on, each allocation gets a random port
+    pub bind: String,
+    pub max_allocations: usize,
+    /// Forwarded to one client, packets over it are dropped
+    pub max_kbps: u32,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            bind: "127.0.0.1".to_string(),
+            max_allocations: 64,
+            max_kbps: 8000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayAllocation {
+    /// On the server's host, other clients send this client's video here
+    pub port: u16,
+    pub token: RelayToken,
+}
+
+#[derive(Debug, Default)]
+struct Peers {
+    /// Where the owner claimed the allocation from
+    owner: Option<SocketAddr>,
+    /// Addresses of the owner's lobby, nothing else gets forwarded
+    permissions: HashSet<IpAddr>,
+}
+
+/// Forwarded bytes, refilled at the cap and holding at most a second's worth
+#[derive(Debug)]
+struct Bandwidth {
+    bytes_per_sec: f64,
+    available: f64,
+    refilled_at: time::Instant,
+}
+
+impl Bandwidth {
+    fn new(max_kbps: u32) -> Self {
+        let bytes_per_sec = max_kbps as f64 * 1000.0 / 8.0;
+        Self {
+            bytes_per_sec,
+            available: bytes_per_sec,
+            refilled_at: time::Instant::now(),
+        }
+    }
+
+    fn take(&mut self, len: usize) -> bool {
+        let now = time::Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.available = (self.available + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+        self.refilled_at = now;
+        if self.available < len as f64 {
+            return false;
+        }
+        self.available -= len as f64;
+        true
+    }
+}
+
+#[derive(Debug)]
+struct Allocation {
+    port: u16,
+    token: RelayToken,
+    peers: Arc<Mutex<Peers>>,
+    // drop
+    _forward: smol::Task<()>,
+}
+
+/// Receives for clients that other clients can't reach directly.
+///
+/// Every client can allocate one port, lobby members send to it
+/// and the relay forwards to the client from that same port,
+/// which gets through nats that only let replies in
+#[derive(Debug, Clone)]
+pub struct Relay {
+    config: RelayConfig,
+    allocations: Arc<Mutex<HashMap<TcpId, Allocation>>>,
+}
+
+impl Relay {
+    pub fn new(config: RelayConfig) -> Self {
+        Self {
+            config,
+            allocations: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Port and token for `id`, reusing its allocation if it has one.
+    /// `None` if the relay is disabled or full
+    pub async fn allocate(&self, id: &TcpId) -> io::Result<Option<RelayAllocation>> {
+        if let Some(v) = self.allocations.lock().unwrap().get(id) {
+            return Ok(Some(RelayAllocation {
+                port: v.port,
+                token: v.token,
+            }));
+        }
+        if !self.config.enabled
+            || self.allocations.lock().unwrap().len() >= self.config.max_allocations
+        {
+            return Ok(None);
+        }
+
+        let socket = UdpSocket::bind((self.config.bind.as_str(), 0)).await?;
+        let port = socket.local_addr()?.port();
+        let mut token = [0; 16];
+        rand_bytes(&mut token)?;
+        let peers = Arc::new(Mutex::new(Peers::default()));
+        let forward = smol::spawn(forward(
+            socket,
+            token,
+            peers.clone(),
+            Bandwidth::new(self.config.max_kbps),
+        ));
+
+        log::debug!("relay: allocated port {port}");
+        self.allocations.lock().unwrap().insert(
+            *id,
+            Allocation {
+                port,
+                token,
+                peers,
+                _forward: forward,
+            },
+        );
+        Ok(Some(RelayAllocation { port, token }))
+    }
+
+    /// Lets `ips` send to the allocation of `id`, if it has one
+    pub fn set_permissions(&self, id: &TcpId, ips: HashSet<IpAddr>) {
+        if let Some(v) = self.allocations.lock().unwrap().get(id) {
+            v.peers.lock().unwrap().permissions = ips;
+        }
+    }
+
+    /// Closes the port of `id`
+    pub fn release(&self, id: &TcpId) {
+        if let Some(v) = self.allocations.lock().unwrap().remove(id) {
+            log::debug!("relay: released port {}", v.port);
+        }
+    }
+}
+
+async fn forward(
+    socket: UdpSocket,
+    token: RelayToken,
+    peers: Arc<Mutex<Peers>>,
+    mut bandwidth: Bandwidth,
+) {
+    let mut buf = vec![0; MAX_PACKET_SIZE];
+    loop {
+        let Ok((len, from)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let packet = &buf[..len];
+        let owner = {
+            let mut peers = peers.lock().unwrap();
+            if packet == token {
+                peers.owner = Some(from);
+                continue;
+            }
+            if !peers.permissions.contains(&from.ip().to_canonical()) {
+                continue;
+            }
+            peers.owner
+        };
+        let Some(owner) = owner else {
+            continue;
+        };
+        if !bandwidth.take(len) {
+            log::trace!("relay: over the cap, dropping {len} bytes");
+            continue;
+        }
+        if let Err(e) = socket.send_to(packet, owner).await {
+            log::debug!("relay: forwarding failed: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn relay(max_allocations: usize) -> Relay {
+        Relay::new(RelayConfig {
+            max_allocations,
+            ..RelayConfig::default()
+        })
+    }
+
+    async fn socket() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    /// What `socket` got within a moment, `None` if nothing came
+    async fn recv(socket: &UdpSocket) -> Option<Vec<u8>> {
+        let mut buf = [0; 64];
+        let recv = async { Some(socket.recv(&mut buf).await.unwrap()) };
+        let timeout = async {
+            smol::Timer::after(time::Duration::from_millis(200)).await;
+            None
+        };
+        let len = smol::future::or(recv, timeout).await?;
+        Some(buf[..len].to_vec())
+    }
+
+    #[test]
+    fn wrong_token_doesnt_claim() {
+        smol::block_on(async {
+            let relay = relay(4);
+            let allocation = relay.allocate(&[1; 32]).await.unwrap().unwrap();
+            relay.set_permissions(&[1; 32], HashSet::from([Ipv4Addr::LOCALHOST.into()]));
+            let relay_addr = ("127.0.0.1", allocation.port);
+            let (owner, peer) = (socket().await, socket().await);
+
+            let mut wrong = allocation.token;
+            wrong[0] ^= 1;
+            owner.send_to(&wrong, relay_addr).await.unwrap();
+            peer.send_to(b"video", relay_addr).await.unwrap();
+            assert_eq!(recv(&owner).await, None);
+
+            owner.send_to(&allocation.token, relay_addr).await.unwrap();
+            peer.send_to(b"video", relay_addr).await.unwrap();
+            assert_eq!(recv(&owner).await.unwrap(), b"video");
+        });
+    }
+
+    #[test]
+    fn only_permitted_ips_are_forwarded() {
+        smol::block_on(async {
+            let relay = relay(4);
+            let allocation = relay.allocate(&[1; 32]).await.unwrap().unwrap();
+            let relay_addr = ("127.0.0.1", allocation.port);
+            let (owner, peer) = (socket().await, socket().await);
+            owner.send_to(&allocation.token, relay_addr).await.unwrap();
+
+            relay.set_permissions(&[1; 32], HashSet::from([Ipv4Addr::new(10, 0, 0, 1).into()]));
+            peer.send_to(b"video", relay_addr).await.unwrap();
+            assert_eq!(recv(&owner).await, None);
+
+            relay.set_permissions(&[1; 32], HashSet::from([Ipv4Addr::LOCALHOST.into()]));
+            peer.send_to(b"video", relay_addr).await.unwrap();
+            assert_eq!(recv(&owner).await.unwrap(), b"video");
+        });
+    }
+
+    #[test]
+    fn bandwidth_refills_up_to_a_second() {
+        // 1000 bytes a second
+        let mut bandwidth = Bandwidth::new(8);
+        assert!(bandwidth.take(1000));
+        assert!(!bandwidth.take(100));
+
+        bandwidth.refilled_at -= time::Duration::from_millis(500);
+        assert!(bandwidth.take(400));
+
+        // idle for long, still only a second's worth
+        bandwidth.refilled_at -= time::Duration::from_secs(10);
+        assert!(bandwidth.take(1000));
+        assert!(!bandwidth.take(100));
+    }
+
+    #[test]
+    fn release_frees_the_port() {
+        smol::block_on(async {
+            let relay = relay(1);
+            let allocation = relay.allocate(&[1; 32]).await.unwrap().unwrap();
+            assert!(relay.allocate(&[2; 32]).await.unwrap().is_none());
+
+            relay.release(&[1; 32]);
+            assert!(relay.allocate(&[2; 32]).await.unwrap().is_some());
+
+            // the forward task drops the socket once the executor gets to it
+            let mut rebound = false;
+            for _ in 0..50 {
+                if UdpSocket::bind(("127.0.0.1", allocation.port))
+                    .await
+                    .is_ok()
+                {
+                    rebound = true;
+                    break;
+                }
+                smol::Timer::after(time::Duration::from_millis(20)).await;
+            }
+            assert!(rebound);
+        });
+    }
+}
@@ -1,243 +1,1123 @@
-use std::{cell::RefCell, collections::HashMap, io, net::SocketAddr, time};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, Weak},
+    time,
+};
 
-use futures::{channel::mpsc, prelude::*};
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+    stream::FuturesUnordered,
+};
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use smol::{
     io::{BufReader, BufWriter},
-    net::{TcpStream, UdpSocket},
+    net::UdpSocket,
+};
+
+use crate::{
+    codec::Codec,
+    conn::TcpId,
+    mux::{DEFAULT_MAX_FRAME_SIZE, MuxChannel},
+    relay,
+    service::rpc_service,
+    session::Sessions,
+    state,
 };
 
-use crate::{conn::TcpId, state};
+pub type RequestId = u32;
+
+/// Most requests a single connection may have in flight on the server
+const MAX_IN_FLIGHT: usize = 32;
+
+/// Request code of heartbeat pings, answered with an empty response
+pub const PING_CODE: u32 = u32::MAX;
+
+const RESPONSE_OK: u8 = 0;
+const RESPONSE_ERROR: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RpcErrorCode {
+    Internal,
+    UnknownCode,
+    InvalidRequest,
+    NotInLobby,
+    TooManyLobbies,
+    PayloadTooLarge,
+    /// The lobby is protected and no password or invite was given
+    CredentialsRequired,
+    /// The password was wrong or the invite expired or belongs elsewhere
+    InvalidCredentials,
+    /// Only the host of the lobby can make the call
+    NotHost,
+    /// The host banned the client from the lobby
+    Banned,
+    /// The lobby has as many members as it allows
+    LobbyFull,
+    /// The lobby has as many streamers as it allows
+    TooManyStreamers,
+    /// The relay is disabled or has no ports left
+    RelayUnavailable,
+}
+
+impl RpcErrorCode {
+    /// Whether the same call can succeed later without the client changing anything
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            Self::Internal
+                | Self::TooManyLobbies
+                | Self::LobbyFull
+                | Self::TooManyStreamers
+                | Self::RelayUnavailable
+        )
+    }
+}
+
+/// Error returned in place of a response, the connection stays usable
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: RpcErrorCode,
+    pub message: String,
+    pub retryable: bool,
+}
+
+impl RpcError {
+    pub fn new(code: RpcErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            retryable: code.is_retryable(),
+        }
+    }
+
+    pub(crate) fn internal(e: impl Into<anyhow::Error>) -> Self {
+        let e = e.into();
+        log::warn!("rpc internal error: {}", e);
+        Self::new(RpcErrorCode::Internal, "internal server error")
+    }
+
+    fn invalid_request(e: anyhow::Error) -> Self {
+        Self::new(RpcErrorCode::InvalidRequest, e.to_string())
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for RpcError {}
 
+/// Failed call, either rejected by the peer or the connection broke
 #[derive(Debug)]
-pub struct RpcConn<T: AsyncRead + AsyncWrite> {
-    writer: BufWriter<T>,
-    reader: BufReader<T>,
+pub enum CallError {
+    Rpc(RpcError),
+    Connection(anyhow::Error),
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rpc(e) => write!(f, "rpc error: {}", e),
+            Self::Connection(e) => write!(f, "connection error: {}", e),
+        }
+    }
 }
 
-impl From<TcpStream> for RpcConn<TcpStream> {
-    fn from(value: TcpStream) -> Self {
-        Self::new(value)
+impl std::error::Error for CallError {}
+
+impl From<io::Error> for CallError {
+    fn from(value: io::Error) -> Self {
+        Self::Connection(value.into())
     }
 }
 
-impl<T: AsyncRead + AsyncWrite + Clone> RpcConn<T> {
-    pub fn new(reader_writer: T) -> Self {
+/// How often the calling side pings and how long either side waits
+/// for the peer before giving up on the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub interval: time::Duration,
+    pub timeout: time::Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
         Self {
-            writer: BufWriter::new(reader_writer.clone()),
-            reader: BufReader::new(reader_writer),
+            interval: time::Duration::from_secs(10),
+            timeout: time::Duration::from_secs(30),
+        }
+    }
+}
+
+impl Heartbeat {
+    /// Whether pings go out often enough to arrive before the timeout
+    pub fn is_valid(&self) -> bool {
+        !self.interval.is_zero() && self.interval < self.timeout
+    }
+}
+
+#[derive(Debug)]
+pub struct RpcReader<T> {
+    reader: BufReader<T>,
+    codec: Codec,
+    max_frame_size: usize,
+    heartbeat: Option<Heartbeat>,
+}
+
+#[derive(Debug)]
+pub struct RpcWriter<T> {
+    writer: BufWriter<T>,
+    codec: Codec,
+}
+
+#[derive(Debug)]
+pub struct RpcConn<T: AsyncRead + AsyncWrite> {
+    writer: RpcWriter<T>,
+    reader: RpcReader<T>,
+}
+
+/// Codes a request is dispatched on
+pub trait CallCode: From<u32> + Copy {
+    /// Bigger payloads are skipped without being read into memory
+    fn max_payload_len(self) -> usize;
+}
+
+/// A received request, `data` is an error if its payload was refused
+#[derive(Debug)]
+pub struct Request<Code> {
+    pub code: Code,
+    pub id: RequestId,
+    pub data: Result<Vec<u8>, RpcError>,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Clone> RpcConn<T> {
+    pub fn new(reader_writer: T, codec: Codec) -> Self {
+        Self {
+            writer: RpcWriter::new(reader_writer.clone(), codec),
+            reader: RpcReader::new(reader_writer, codec),
         }
     }
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin> RpcConn<T> {
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.reader = self.reader.with_max_frame_size(max_frame_size);
+        self
+    }
+
+    /// Pings the peer when used for calls, times out when handling them
+    /// and no request, pings included, arrives for `heartbeat.timeout`
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.reader = self.reader.with_heartbeat(heartbeat);
+        self
+    }
+
     pub fn into_inner(self) -> T {
-        self.writer.into_inner()
+        self.writer.writer.into_inner()
     }
 
-    async fn call_raw<Code: Into<u32>>(&mut self, code: Code, data: &[u8]) -> io::Result<Vec<u8>> {
-        self.send_code(code).await?;
-        self.send_data(data).await?;
-        self.recv_data().await
+    pub fn split(self) -> (RpcReader<T>, RpcWriter<T>) {
+        (self.reader, self.writer)
     }
 
-    async fn call<Code: Into<u32>, S: Serialize, D: DeserializeOwned>(
-        &mut self,
-        code: Code,
-        data: S,
-    ) -> anyhow::Result<D> {
-        let ret = self
-            .call_raw(code, serde_json::to_string(&data)?.as_bytes())
-            .await?;
-        Ok(serde_json::from_slice(&ret)?)
+    async fn recv_call<Code: CallCode>(&mut self) -> io::Result<Request<Code>> {
+        self.reader.recv_request().await
     }
+}
 
-    async fn recv_call<Code: From<u32>>(&mut self) -> io::Result<(Code, Vec<u8>)> {
-        let code = self.recv_code::<Code>().await?;
-        let data = self.recv_data().await?;
-        Ok((code, data))
+impl<T: AsyncRead + Unpin> RpcReader<T> {
+    pub fn new(reader: T, codec: Codec) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            codec,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            heartbeat: None,
+        }
+    }
+
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = Some(heartbeat);
+        self
+    }
+
+    /// Fails with [`io::ErrorKind::TimedOut`] if a heartbeat is set
+    /// and the peer sends nothing for its timeout
+    pub async fn recv_request<Code: CallCode>(&mut self) -> io::Result<Request<Code>> {
+        let Some(heartbeat) = self.heartbeat else {
+            return self.read_request().await;
+        };
+        // a request cut off by the timeout leaves the stream unusable,
+        // which is fine since the connection is given up anyway
+        smol::future::or(self.read_request(), async {
+            smol::Timer::after(heartbeat.timeout).await;
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "peer missed its heartbeat",
+            ))
+        })
+        .await
     }
 
-    async fn recv_call_ret<S: Serialize>(&mut self, data: S) -> anyhow::Result<()> {
-        Ok(self
-            .send_data(serde_json::to_string(&data)?.as_bytes())
-            .await?)
+    async fn read_request<Code: CallCode>(&mut self) -> io::Result<Request<Code>> {
+        let code = Code::from(self.read_u32().await?);
+        let id = self.read_u32().await?;
+        let len = self.read_len().await?;
+
+        let max_len = code.max_payload_len();
+        let data = if len > max_len {
+            // keep the stream in sync without buffering the payload
+            futures::io::copy((&mut self.reader).take(len as _), &mut futures::io::sink()).await?;
+            Err(RpcError::new(
+                RpcErrorCode::PayloadTooLarge,
+                format!("payload of {len} bytes is over the limit of {max_len}"),
+            ))
+        } else {
+            Ok(self.read_data(len).await?)
+        };
+        Ok(Request { code, id, data })
     }
 
-    async fn recv_data(&mut self) -> io::Result<Vec<u8>> {
+    pub async fn recv_response(&mut self) -> io::Result<(RequestId, Result<Vec<u8>, RpcError>)> {
+        let id = self.read_u32().await?;
+        let mut status = [0; 1];
+        self.reader.read_exact(&mut status).await?;
         let len = self.read_len().await?;
-        let mut data = Vec::with_capacity(len as _);
-        unsafe { data.set_len(len as _) }
-        self.reader.read_exact(&mut data).await?;
+        let data = self.read_data(len).await?;
+        match status[0] {
+            RESPONSE_OK => Ok((id, Ok(data))),
+            RESPONSE_ERROR => {
+                let error = self
+                    .codec
+                    .decode(&data)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Ok((id, Err(error)))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid response status",
+            )),
+        }
+    }
 
+    /// Never ends, yields the read error until the caller stops polling
+    fn into_requests<Code: CallCode>(self) -> impl Stream<Item = io::Result<Request<Code>>> {
+        futures::stream::unfold(self, |mut v| async move {
+            let request = v.recv_request().await;
+            Some((request, v))
+        })
+    }
+
+    /// Payload length, anything over the frame size can't be valid
+    async fn read_len(&mut self) -> io::Result<usize> {
+        let len = self.read_u32().await? as usize;
+        if len > self.max_frame_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "payload of {len} bytes is over the frame limit of {}",
+                    self.max_frame_size
+                ),
+            ));
+        }
+        Ok(len)
+    }
+
+    /// Grows the buffer as data arrives instead of trusting `len` up front
+    async fn read_data(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        (&mut self.reader)
+            .take(len as _)
+            .read_to_end(&mut data)
+            .await?;
+        if data.len() != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
         Ok(data)
     }
 
-    async fn read_len(&mut self) -> io::Result<u32> {
-        let mut len = [0; 4];
-        self.reader.read_exact(&mut len).await?;
-        Ok(u32::from_le_bytes(len))
+    async fn read_u32(&mut self) -> io::Result<u32> {
+        let mut v = [0; 4];
+        self.reader.read_exact(&mut v).await?;
+        Ok(u32::from_le_bytes(v))
     }
+}
 
-    async fn write_len(&mut self, len: u32) -> io::Result<()> {
-        self.writer.write_all(&len.to_le_bytes()).await
+impl<T: AsyncWrite + Unpin> RpcWriter<T> {
+    pub fn new(writer: T, codec: Codec) -> Self {
+        Self {
+            writer: BufWriter::new(writer),
+            codec,
+        }
     }
 
-    async fn send_code<Code: Into<u32>>(&mut self, code: Code) -> io::Result<()> {
-        self.writer.write_all(&(code.into()).to_le_bytes()).await
+    pub async fn send_request<Code: Into<u32>>(
+        &mut self,
+        code: Code,
+        id: RequestId,
+        data: &[u8],
+    ) -> io::Result<()> {
+        self.write_u32(code.into()).await?;
+        self.write_u32(id).await?;
+        self.send_data(data).await
     }
 
-    async fn recv_code<Code: From<u32>>(&mut self) -> io::Result<Code> {
-        let mut code = [0; 4];
-        self.reader.read_exact(&mut code).await?;
-        Ok(u32::from_le_bytes(code).into())
+    pub async fn send_response(
+        &mut self,
+        id: RequestId,
+        ret: Result<Vec<u8>, RpcError>,
+    ) -> io::Result<()> {
+        self.write_u32(id).await?;
+        match ret {
+            Ok(data) => {
+                self.writer.write_all(&[RESPONSE_OK]).await?;
+                self.send_data(&data).await
+            }
+            Err(e) => {
+                self.writer.write_all(&[RESPONSE_ERROR]).await?;
+                let error = self
+                    .codec
+                    .encode(&e)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                self.send_data(&error).await
+            }
+        }
+    }
+
+    async fn write_u32(&mut self, v: u32) -> io::Result<()> {
+        self.writer.write_all(&v.to_le_bytes()).await
     }
 
     async fn send_data(&mut self, data: &[u8]) -> io::Result<()> {
-        self.write_len(data.len() as _).await?;
+        self.write_u32(data.len() as _).await?;
         self.writer.write_all(data).await?;
         self.writer.flush().await?;
         Ok(())
     }
 }
 
-#[repr(u32)]
-pub enum RpcCode {
-    Unknown = 0,
-    JoinLobby = 1,
-    StartStream = 2,
+#[derive(Debug, Default)]
+struct PendingCalls {
+    next_id: RequestId,
+    waiting: HashMap<RequestId, oneshot::Sender<Result<Vec<u8>, RpcError>>>,
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct RpcCallerInner<T> {
+    writer: smol::lock::Mutex<RpcWriter<T>>,
+    codec: Codec,
+    pending: Arc<Mutex<PendingCalls>>,
+    // stops the dispatcher once the last caller is gone
+    _closed_tx: oneshot::Sender<()>,
 }
 
-impl From<u32> for RpcCode {
-    fn from(value: u32) -> Self {
-        match value {
-            1 => Self::JoinLobby,
-            2 => Self::StartStream,
-            _ => Self::Unknown,
+/// Calling side of a connection, matches responses to calls by request id
+/// so any number of calls can be in flight at once
+#[derive(Debug)]
+pub struct RpcCaller<T> {
+    inner: Arc<RpcCallerInner<T>>,
+}
+
+impl<T> Clone for RpcCaller<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
         }
     }
 }
 
-#[repr(u32)]
-pub enum RpcNotifyCode {
-    Unknown = 0,
-    LobbyInfo = 1,
-}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> RpcCaller<T> {
+    pub fn new(connection: RpcConn<T>) -> Self {
+        let (reader, writer) = connection.split();
+        let pending = Arc::new(Mutex::new(PendingCalls::default()));
+        let (closed_tx, closed_rx) = oneshot::channel();
+
+        let inner = Arc::new(RpcCallerInner {
+            codec: writer.codec,
+            writer: smol::lock::Mutex::new(writer),
+            pending: pending.clone(),
+            _closed_tx: closed_tx,
+        });
+        let heartbeat = reader.heartbeat;
+        let ping = Self::ping(Arc::downgrade(&inner), heartbeat);
+
+        smol::spawn(Self::dispatch(reader, pending, closed_rx, ping)).detach();
+
+        Self { inner }
+    }
+
+    /// Whether the connection closed or stopped answering heartbeats
+    pub fn is_closed(&self) -> bool {
+        self.inner.pending.lock().unwrap().closed
+    }
+
+    async fn dispatch(
+        reader: RpcReader<T>,
+        pending: Arc<Mutex<PendingCalls>>,
+        closed_rx: oneshot::Receiver<()>,
+        ping: impl Future<Output = ()>,
+    ) {
+        let responses = futures::stream::unfold(reader, |mut v| async move {
+            let response = v.recv_response().await.ok()?;
+            Some((response, v))
+        })
+        .take_until(closed_rx);
+
+        let deliver = async {
+            let mut responses = std::pin::pin!(responses);
+            while let Some((id, ret)) = responses.next().await {
+                if let Some(tx) = pending.lock().unwrap().waiting.remove(&id) {
+                    let _ = tx.send(ret);
+                }
+            }
+        };
+        // pongs are delivered like any other response, so both run side by side
+        smol::future::or(deliver, ping).await;
+
+        let mut pending = pending.lock().unwrap();
+        pending.closed = true;
+        pending.waiting.clear();
+    }
 
-impl From<u32> for RpcNotifyCode {
-    fn from(value: u32) -> Self {
-        match value {
-            1 => Self::LobbyInfo,
-            _ => Self::Unknown,
+    /// Returns once the peer misses a heartbeat, never without one
+    async fn ping(inner: Weak<RpcCallerInner<T>>, heartbeat: Option<Heartbeat>) {
+        let Some(heartbeat) = heartbeat else {
+            return future::pending().await;
+        };
+        loop {
+            smol::Timer::after(heartbeat.interval).await;
+            let Some(inner) = inner.upgrade() else {
+                // the dispatcher is stopping anyway
+                return future::pending().await;
+            };
+            let caller = Self { inner };
+            let pong = smol::future::or(caller.call_raw(PING_CODE, &[]).map(Some), async {
+                smol::Timer::after(heartbeat.timeout).await;
+                None
+            })
+            .await;
+            match pong {
+                // an error response still proves the peer is there
+                Some(Ok(_) | Err(CallError::Rpc(_))) => {}
+                Some(Err(e)) => {
+                    log::debug!("heartbeat failed: {}", e);
+                    return;
+                }
+                None => {
+                    log::debug!("peer missed its heartbeat");
+                    return;
+                }
+            }
         }
     }
+
+    async fn call_raw<Code: Into<u32>>(
+        &self,
+        code: Code,
+        data: &[u8],
+    ) -> Result<Vec<u8>, CallError> {
+        let (tx, rx) = oneshot::channel();
+        let id = {
+            let mut pending = self.inner.pending.lock().unwrap();
+            if pending.closed {
+                return Err(CallError::Connection(anyhow::anyhow!("connection closed")));
+            }
+            let id = pending.next_id;
+            pending.next_id = id.wrapping_add(1);
+            pending.waiting.insert(id, tx);
+            id
+        };
+
+        let sent = self
+            .inner
+            .writer
+            .lock()
+            .await
+            .send_request(code, id, data)
+            .await;
+        if let Err(e) = sent {
+            self.inner.pending.lock().unwrap().waiting.remove(&id);
+            return Err(e.into());
+        }
+
+        rx.await
+            .map_err(|_| {
+                CallError::Connection(anyhow::anyhow!("connection closed before response"))
+            })?
+            .map_err(CallError::Rpc)
+    }
+
+    async fn call<Code: Into<u32>, S: Serialize, D: DeserializeOwned>(
+        &self,
+        code: Code,
+        data: S,
+    ) -> Result<D, CallError> {
+        let codec = self.inner.codec;
+        let data = codec.encode(&data).map_err(CallError::Connection)?;
+        let ret = self.call_raw(code, &data).await?;
+        codec.decode(&ret).map_err(CallError::Connection)
+    }
+}
+
+rpc_service! {
+    /// Calls from the app to the server
+    service RpcCode, RpcUserClient, RpcUserHandler {
+        1 => JoinLobby, join_lobby(JoinLobbyData) -> JoinLobbyRet, max 1024;
+        /// Announces a stream, it is starting until the caller reports it live
+        2 => StartStream, start_stream(state::StreamMetadata) -> VoidRet, max 1024;
+        /// Current state of the lobby, for when a delta was missed
+        3 => LobbySnapshot, lobby_snapshot(VoidRet) -> state::LobbyInfoData, max 64;
+        /// Password and invite rules for new clients, host only
+        4 => SetLobbyAccess, set_lobby_access(LobbyAccessData) -> VoidRet, max 1024;
+        /// Signed token that lets its holder into the lobby until it expires, host only
+        5 => CreateInvite, create_invite(CreateInviteData) -> CreateInviteRet, max 64;
+        /// Removes a client from the lobby, host only
+        6 => Kick, kick(TargetData) -> VoidRet, max 128;
+        /// Removes a client and keeps it from joining again, host only
+        7 => Ban, ban(TargetData) -> VoidRet, max 128;
+        /// Stops the stream of a client, host only
+        8 => StopClientStream, stop_client_stream(TargetData) -> VoidRet, max 128;
+        /// Makes another client the host, host only
+        9 => TransferHost, transfer_host(TargetData) -> VoidRet, max 128;
+        /// Member and streamer limits within the server's, host only
+        10 => SetLobbyLimits, set_lobby_limits(state::LobbyLimits) -> VoidRet, max 128;
+        /// Sends a message to everyone in the lobby, the sender included
+        11 => SendChat, send_chat(SendChatData) -> VoidRet, max 4096;
+        /// Creates a lobby with a server picked id and joins it as the host
+        12 => CreateLobby, create_lobby(CreateLobbyData) -> CreateLobbyRet, max 1024;
+        /// Lobbies anyone can find, works without being in a lobby
+        13 => ListLobbies, list_lobbies(VoidRet) -> Vec<state::PublicLobby>, max 64;
+        /// Ends the stream of the caller
+        14 => StopStream, stop_stream(VoidRet) -> VoidRet, max 64;
+        /// The caller's stream went live, paused or failed
+        15 => UpdateStream, update_stream(state::StreamUpdate) -> VoidRet, max 64;
+        /// Starts receiving the stream of a client, it sends its video to its watchers only
+        16 => Subscribe, subscribe(TargetData) -> VoidRet, max 128;
+        /// Stops receiving the stream of a client
+        17 => Unsubscribe, unsubscribe(TargetData) -> VoidRet, max 128;
+        /// Port on the server that lobby members send to when the caller can't be reached
+        /// directly, the caller claims it by sending the token there from its udp socket
+        18 => AllocateRelay, allocate_relay(VoidRet) -> relay::RelayAllocation, max 64;
+        /// Leaves the lobby and ends the session at once, the caller closes the connection after
+        19 => LeaveLobby, leave_lobby(VoidRet) -> VoidRet, max 64;
+    }
+}
+
+rpc_service! {
+    /// Calls from the server to the app
+    service RpcNotifyCode, RpcNotifyClient, RpcNotifyHandler {
+        1 => LobbyInfo, lobby_info(state::LobbyInfoData) -> VoidRet, max 256 * 1024;
+        2 => LobbyDelta, lobby_delta(state::LobbyDelta) -> VoidRet, max 4096;
+        3 => Chat, chat(state::ChatMessage) -> VoidRet, max 4096;
+        /// Messages sent before the client joined, oldest first
+        4 => ChatHistory, chat_history(Vec<state::ChatMessage>) -> VoidRet, max 256 * 1024;
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VoidRet {}
 
+#[derive(Debug, Clone)]
+pub struct RpcServerConfig {
+    pub udp_bind: String,
+    pub udp_port: u16,
+    pub max_lobbies: usize,
+    /// Defaults for new lobbies and the most their hosts can allow
+    pub lobby_limits: state::LobbyLimits,
+    pub relay: relay::RelayConfig,
+}
+
 #[derive(Debug, Clone)]
 pub struct RpcServer {
+    config: RpcServerConfig,
     lobbies: crate::ArcMu<state::Lobbies>,
+    sessions: Sessions,
     notify_tx: mpsc::Sender<Notify>,
+    relay: relay::Relay,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct JoinLobbyData {
     pub id: String,
+    #[serde(default)]
+    pub credentials: state::Credentials,
+    pub profile: state::Profile,
 }
 
-#[derive(Debug)]
-pub struct RpcUserClient {
-    connection: RpcConn<TcpStream>,
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CreateLobbyData {
+    /// A password protects the new lobby, an invite is meaningless
+    #[serde(default)]
+    pub credentials: state::Credentials,
+    pub profile: state::Profile,
+    /// Listed by [`RpcUserClient::list_lobbies`]
+    pub public: bool,
 }
 
-impl RpcUserClient {
-    pub fn new(connection: RpcConn<TcpStream>) -> Self {
-        Self { connection }
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LobbyAccessData {
+    /// `None` removes the password
+    pub password: Option<String>,
+    /// Refuse passwords too, only invites let new clients in
+    pub invite_only: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CreateInviteData {
+    /// Capped at [`state::MAX_INVITE_TTL`]
+    pub ttl_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CreateInviteRet {
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CreateLobbyRet {
+    pub id: String,
+    /// Where the client should send `udp_token` over udp
+    pub udp_port: u16,
+    pub udp_token: state::UdpToken,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SendChatData {
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TargetData {
+    /// Client of the lobby the call is about
+    pub id: state::MemberId,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JoinLobbyRet {
+    /// Where the client should send `udp_token` over udp
+    pub udp_port: u16,
+    pub udp_token: state::UdpToken,
+}
+
+/// Something the server pushed to a client
+#[derive(Debug, Clone)]
+pub enum Notification {
+    LobbyInfo(state::LobbyInfoData),
+    LobbyDelta(state::LobbyDelta),
+    Chat(state::ChatMessage),
+    ChatHistory(Vec<state::ChatMessage>),
+}
+
+/// Keeps what the server sent so the notify stream can yield it
+#[derive(Debug, Default)]
+struct NotifyCollector {
+    notification: RefCell<Option<Notification>>,
+}
+
+impl RpcNotifyHandler for NotifyCollector {
+    async fn lobby_info(&self, data: state::LobbyInfoData) -> Result<VoidRet, RpcError> {
+        *self.notification.borrow_mut() = Some(Notification::LobbyInfo(data));
+        Ok(VoidRet {})
+    }
+
+    async fn lobby_delta(&self, data: state::LobbyDelta) -> Result<VoidRet, RpcError> {
+        *self.notification.borrow_mut() = Some(Notification::LobbyDelta(data));
+        Ok(VoidRet {})
     }
 
-    pub async fn join_lobby(&mut self, data: JoinLobbyData) -> anyhow::Result<VoidRet> {
-        Ok(self
-            .connection
-            .call(RpcCode::JoinLobby as u32, data)
-            .await?)
+    async fn chat(&self, data: state::ChatMessage) -> Result<VoidRet, RpcError> {
+        *self.notification.borrow_mut() = Some(Notification::Chat(data));
+        Ok(VoidRet {})
     }
 
-    pub async fn start_stream(&mut self) -> anyhow::Result<VoidRet> {
-        Ok(self
-            .connection
-            .call(RpcCode::StartStream as u32, VoidRet {})
-            .await?)
+    async fn chat_history(&self, data: Vec<state::ChatMessage>) -> Result<VoidRet, RpcError> {
+        *self.notification.borrow_mut() = Some(Notification::ChatHistory(data));
+        Ok(VoidRet {})
     }
 }
 
 pub fn rpc_user_notify_stream(
-    connection: RpcConn<TcpStream>,
-) -> impl TryStream<Item = anyhow::Result<state::LobbyInfoData>> {
+    connection: RpcConn<MuxChannel>,
+) -> impl TryStream<Item = anyhow::Result<Notification>> {
     futures::stream::try_unfold(connection, |mut v| async {
-        let (code, data) = v.recv_call::<RpcNotifyCode>().await?;
-        match code {
-            RpcNotifyCode::Unknown => anyhow::bail!("unknown code"),
-            RpcNotifyCode::LobbyInfo => {
-                v.recv_call_ret(VoidRet {}).await?;
-                Ok(Some((
-                    serde_json::from_slice::<state::LobbyInfoData>(&data)?,
-                    v,
-                )))
+        let collector = NotifyCollector::default();
+        loop {
+            let request = v.recv_call::<RpcNotifyCode>().await?;
+            let id = request.id;
+            let ret = collector.dispatch(v.reader.codec, request).await;
+            v.writer.send_response(id, ret).await?;
+
+            if let Some(notification) = collector.notification.take() {
+                return Ok(Some((notification, v)));
             }
         }
     })
 }
 
+/// Decodes the call data, runs the handler and encodes what it returned
+pub(crate) async fn call_handler<D, R, F>(
+    codec: Codec,
+    data: Result<Vec<u8>, RpcError>,
+    handler: impl FnOnce(D) -> F,
+) -> Result<Vec<u8>, RpcError>
+where
+    D: DeserializeOwned,
+    R: Serialize,
+    F: Future<Output = Result<R, RpcError>>,
+{
+    let data = codec.decode(&data?).map_err(RpcError::invalid_request)?;
+    let ret = handler(data).await?;
+    codec.encode(&ret).map_err(RpcError::internal)
+}
+
 #[derive(Debug)]
 pub struct RpcServerHandler {
     id: TcpId,
+    addr: IpAddr,
     server: RpcServer,
-    connection: RpcConn<TcpStream>,
+    // taken by listen
+    connection: Option<RpcConn<MuxChannel>>,
 }
 
-impl RpcServerHandler {
-    async fn handle_join_lobby(&self, data: JoinLobbyData) -> anyhow::Result<VoidRet> {
-        self.server.lobbies.lock().await.join(
+impl RpcUserHandler for RpcServerHandler {
+    async fn leave_lobby(&self, _data: VoidRet) -> Result<VoidRet, RpcError> {
+        if self
+            .server
+            .lobbies
+            .lock()
+            .await
+            .get_tcp_id_lobby(&self.id)
+            .is_none()
+        {
+            return Err(RpcError::new(RpcErrorCode::NotInLobby, "leave no lobby"));
+        }
+        // nothing is left to resume, so don't wait out the grace period
+        self.server.sessions.end(&self.id);
+        self.server
+            .cleanup(&self.id)
+            .await
+            .map_err(RpcError::internal)?;
+        Ok(VoidRet {})
+    }
+
+    async fn join_lobby(&self, data: JoinLobbyData) -> Result<JoinLobbyRet, RpcError> {
+        let mut lobbies = self.server.lobbies.lock().await;
+        let state::Joined {
+            left,
+            left_deltas,
+            delta,
+            udp_token,
+        } = lobbies.join(
             data.id.clone(),
-            state::LobbyClient::new(self.id, None, false),
-        );
-        self.server.notify_lobby(&data.id).await?;
+            state::LobbyClient::new(
+                self.id,
+                data.profile.validated()?,
+                None,
+                state::StreamState::Idle,
+            ),
+            self.addr,
+            &data.credentials,
+        )?;
+        self.server
+            .notify_left(&lobbies, &self.id, left, left_deltas)
+            .await
+            .map_err(RpcError::internal)?;
+        self.server
+            .notify_delta(&lobbies, &data.id, delta)
+            .await
+            .map_err(RpcError::internal)?;
+        self.server
+            .notify_snapshot(&lobbies, &self.id)
+            .await
+            .map_err(RpcError::internal)?;
+        self.server
+            .notify_chat_history(&lobbies, &self.id)
+            .await
+            .map_err(RpcError::internal)?;
+        Ok(JoinLobbyRet {
+            udp_port: self.server.config.udp_port,
+            udp_token,
+        })
+    }
+
+    async fn create_lobby(&self, data: CreateLobbyData) -> Result<CreateLobbyRet, RpcError> {
+        let mut lobbies = self.server.lobbies.lock().await;
+        let (
+            id,
+            state::Joined {
+                left,
+                left_deltas,
+                delta,
+                udp_token,
+            },
+        ) = lobbies.create(
+            state::LobbyClient::new(
+                self.id,
+                data.profile.validated()?,
+                None,
+                state::StreamState::Idle,
+            ),
+            self.addr,
+            &data.credentials,
+            data.public,
+        )?;
+        self.server
+            .notify_left(&lobbies, &self.id, left, left_deltas)
+            .await
+            .map_err(RpcError::internal)?;
+        self.server
+            .notify_delta(&lobbies, &id, delta)
+            .await
+            .map_err(RpcError::internal)?;
+        self.server
+            .notify_snapshot(&lobbies, &self.id)
+            .await
+            .map_err(RpcError::internal)?;
+        Ok(CreateLobbyRet {
+            id,
+            udp_port: self.server.config.udp_port,
+            udp_token,
+        })
+    }
+
+    async fn list_lobbies(&self, _data: VoidRet) -> Result<Vec<state::PublicLobby>, RpcError> {
+        Ok(self.server.lobbies.lock().await.list_public())
+    }
+
+    async fn start_stream(&self, data: state::StreamMetadata) -> Result<VoidRet, RpcError> {
+        let metadata = data.validated()?;
+        let mut lobbies = self.server.lobbies.lock().await;
+        let (lobby_id, delta) = lobbies.start_stream(&self.id, metadata)?;
+        self.server
+            .notify_delta(&lobbies, &lobby_id, delta)
+            .await
+            .map_err(RpcError::internal)?;
+        Ok(VoidRet {})
+    }
+
+    async fn stop_stream(&self, _data: VoidRet) -> Result<VoidRet, RpcError> {
+        let mut lobbies = self.server.lobbies.lock().await;
+        let (lobby_id, delta) = lobbies.stop_stream(&self.id)?;
+        self.server
+            .notify_delta(&lobbies, &lobby_id, delta)
+            .await
+            .map_err(RpcError::internal)?;
+        Ok(VoidRet {})
+    }
+
+    async fn update_stream(&self, data: state::StreamUpdate) -> Result<VoidRet, RpcError> {
+        let mut lobbies = self.server.lobbies.lock().await;
+        let (lobby_id, delta) = lobbies.update_stream(&self.id, data)?;
+        self.server
+            .notify_delta(&lobbies, &lobby_id, delta)
+            .await
+            .map_err(RpcError::internal)?;
         Ok(VoidRet {})
     }
 
-    async fn handle_start_stream(&self, _data: VoidRet) -> anyhow::Result<VoidRet> {
-        let lobby_id = self
+    async fn lobby_snapshot(&self, _data: VoidRet) -> Result<state::LobbyInfoData, RpcError> {
+        let lobbies = self.server.lobbies.lock().await;
+        let lobby = lobbies
+            .get_tcp_id_lobby(&self.id)
+            .ok_or_else(|| RpcError::new(RpcErrorCode::NotInLobby, "snapshot no lobby"))?;
+        Ok(state::LobbyInfoData::for_client(
+            lobby,
+            state::member_id(&self.id),
+        ))
+    }
+
+    async fn set_lobby_access(&self, data: LobbyAccessData) -> Result<VoidRet, RpcError> {
+        self.server.lobbies.lock().await.set_access(
+            &self.id,
+            data.password.as_deref(),
+            data.invite_only,
+        )?;
+        Ok(VoidRet {})
+    }
+
+    async fn create_invite(&self, data: CreateInviteData) -> Result<CreateInviteRet, RpcError> {
+        let token = self
             .server
             .lobbies
             .lock()
             .await
-            .set_client_is_streaming(&self.id, true)
-            .ok_or_else(|| anyhow::anyhow!("start stream no lobby"))?;
-        self.server.notify_lobby(&lobby_id).await?;
+            .create_invite(&self.id, time::Duration::from_secs(data.ttl_secs))?;
+        Ok(CreateInviteRet { token })
+    }
+
+    async fn kick(&self, data: TargetData) -> Result<VoidRet, RpcError> {
+        let mut lobbies = self.server.lobbies.lock().await;
+        let (lobby_id, removed, delta) = lobbies.kick(&self.id, &data.id)?;
+        self.server.relay.release(&removed);
+        self.server
+            .notify_removed(&lobbies, &lobby_id, &removed, delta)
+            .await
+            .map_err(RpcError::internal)?;
         Ok(VoidRet {})
     }
 
+    async fn ban(&self, data: TargetData) -> Result<VoidRet, RpcError> {
+        let mut lobbies = self.server.lobbies.lock().await;
+        let (lobby_id, removed, delta) = lobbies.ban(&self.id, &data.id)?;
+        self.server.relay.release(&removed);
+        self.server
+            .notify_removed(&lobbies, &lobby_id, &removed, delta)
+            .await
+            .map_err(RpcError::internal)?;
+        Ok(VoidRet {})
+    }
+
+    async fn stop_client_stream(&self, data: TargetData) -> Result<VoidRet, RpcError> {
+        let mut lobbies = self.server.lobbies.lock().await;
+        let (lobby_id, delta) = lobbies.stop_client_stream(&self.id, &data.id)?;
+        self.server
+            .notify_delta(&lobbies, &lobby_id, delta)
+            .await
+            .map_err(RpcError::internal)?;
+        Ok(VoidRet {})
+    }
+
+    async fn subscribe(&self, data: TargetData) -> Result<VoidRet, RpcError> {
+        let mut lobbies = self.server.lobbies.lock().await;
+        let (lobby_id, delta) = lobbies.subscribe(&self.id, &data.id)?;
+        self.server
+            .notify_delta(&lobbies, &lobby_id, delta)
+            .await
+            .map_err(RpcError::internal)?;
+        // the delta doesn't carry addresses, the snapshots give the pair each other's
+        let streamer = lobbies
+            .get(&lobby_id)
+            .and_then(|v| v.get_client(&data.id))
+            .map(|v| v.tcp_id);
+        for id in std::iter::once(self.id).chain(streamer) {
+            self.server
+                .notify_snapshot(&lobbies, &id)
+                .await
+                .map_err(RpcError::internal)?;
+        }
+        Ok(VoidRet {})
+    }
+
+    async fn unsubscribe(&self, data: TargetData) -> Result<VoidRet, RpcError> {
+        let mut lobbies = self.server.lobbies.lock().await;
+        let (lobby_id, delta) = lobbies.unsubscribe(&self.id, &data.id)?;
+        self.server
+            .notify_delta(&lobbies, &lobby_id, delta)
+            .await
+            .map_err(RpcError::internal)?;
+        Ok(VoidRet {})
+    }
+
+    async fn allocate_relay(&self, _data: VoidRet) -> Result<relay::RelayAllocation, RpcError> {
+        let mut lobbies = self.server.lobbies.lock().await;
+        if lobbies.get_tcp_id_lobby(&self.id).is_none() {
+            return Err(RpcError::new(RpcErrorCode::NotInLobby, "relay no lobby"));
+        }
+        let allocation = self
+            .server
+            .relay
+            .allocate(&self.id)
+            .await
+            .map_err(RpcError::internal)?
+            .ok_or_else(|| {
+                RpcError::new(
+                    RpcErrorCode::RelayUnavailable,
+                    "relay is disabled or has no ports left",
+                )
+            })?;
+        if let Some((lobby_id, delta)) = lobbies.set_client_relay_port(&self.id, allocation.port) {
+            self.server
+                .notify_delta(&lobbies, &lobby_id, delta)
+                .await
+                .map_err(RpcError::internal)?;
+        }
+        Ok(allocation)
+    }
+
+    async fn set_lobby_limits(&self, data: state::LobbyLimits) -> Result<VoidRet, RpcError> {
+        let mut lobbies = self.server.lobbies.lock().await;
+        let (lobby_id, delta) = lobbies.set_limits(&self.id, data)?;
+        self.server
+            .notify_delta(&lobbies, &lobby_id, delta)
+            .await
+            .map_err(RpcError::internal)?;
+        Ok(VoidRet {})
+    }
+
+    async fn send_chat(&self, data: SendChatData) -> Result<VoidRet, RpcError> {
+        let mut lobbies = self.server.lobbies.lock().await;
+        let (lobby_id, message) = lobbies.send_chat(&self.id, &data.text)?;
+        self.server
+            .notify_lobby(&lobbies, &lobby_id, Notification::Chat(message))
+            .await
+            .map_err(RpcError::internal)?;
+        Ok(VoidRet {})
+    }
+
+    async fn transfer_host(&self, data: TargetData) -> Result<VoidRet, RpcError> {
+        let mut lobbies = self.server.lobbies.lock().await;
+        let (lobby_id, delta) = lobbies.transfer_host(&self.id, &data.id)?;
+        self.server
+            .notify_delta(&lobbies, &lobby_id, delta)
+            .await
+            .map_err(RpcError::internal)?;
+        Ok(VoidRet {})
+    }
+}
+
+impl RpcServerHandler {
+    async fn handle(
+        &self,
+        writer: &smol::lock::Mutex<RpcWriter<MuxChannel>>,
+        codec: Codec,
+        request: Request<RpcCode>,
+    ) -> anyhow::Result<()> {
+        let id = request.id;
+        let ret = self.dispatch(codec, request).await;
+        if let Err(e) = &ret {
+            log::debug!("call failed: {}", e);
+        }
+        writer.lock().await.send_response(id, ret).await?;
+        Ok(())
+    }
+
     pub async fn listen(mut self) -> anyhow::Result<()> {
-        println!("handler.listen");
+        log::debug!("handler.listen");
+        let Some(connection) = self.connection.take() else {
+            anyhow::bail!("handler is already listening");
+        };
+        let (reader, writer) = connection.split();
+        let codec = reader.codec;
+        let writer = smol::lock::Mutex::new(writer);
+
+        let mut requests = std::pin::pin!(reader.into_requests::<RpcCode>().fuse());
+        let mut in_flight = FuturesUnordered::new();
+
         loop {
-            let (code, data) = self.connection.recv_call::<RpcCode>().await?;
-            match code {
-                RpcCode::Unknown => anyhow::bail!("unknown code"),
-                RpcCode::JoinLobby => {
-                    let ret = self
-                        .handle_join_lobby(serde_json::from_slice(&data)?)
-                        .await?;
-                    self.connection.recv_call_ret(ret).await?;
+            if in_flight.len() >= MAX_IN_FLIGHT {
+                if let Some(res) = in_flight.next().await {
+                    res?;
                 }
-                RpcCode::StartStream => {
-                    let ret = self
-                        .handle_start_stream(serde_json::from_slice(&data)?)
-                        .await?;
-                    self.connection.recv_call_ret(ret).await?;
-                }
-            };
+                continue;
+            }
+
+            futures::select! {
+                request = requests.select_next_some() => {
+                    in_flight.push(self.handle(&writer, codec, request?));
+                },
+                res = in_flight.select_next_some() => res?,
+            }
         }
     }
 }
@@ -245,12 +1125,18 @@ impl RpcServerHandler {
 impl Drop for RpcServerHandler {
     fn drop(&mut self) {
         let server = self.server.clone();
-        let id = self.id.clone();
+        let id = self.id;
+        server.sessions.detach(&id);
         smol::spawn(async move {
+            // the client may resume the session until then
+            smol::Timer::after(server.sessions.grace()).await;
+            if !server.sessions.expire(&id) {
+                return;
+            }
             match server.cleanup(&id).await {
                 Ok(_) => {}
                 Err(v) => {
-                    println!("cleaning up server failed: {v}");
+                    log::error!("cleaning up server failed: {v}");
                 }
             };
         })
@@ -259,33 +1145,47 @@ impl Drop for RpcServerHandler {
 }
 
 impl RpcServer {
-    pub fn new(notify_tx: mpsc::Sender<Notify>) -> Self {
+    pub fn new(
+        notify_tx: mpsc::Sender<Notify>,
+        sessions: Sessions,
+        config: RpcServerConfig,
+    ) -> Self {
         Self {
-            lobbies: crate::arcmu(state::Lobbies::new()),
+            lobbies: crate::arcmu(state::Lobbies::new(config.max_lobbies, config.lobby_limits)),
+            relay: relay::Relay::new(config.relay.clone()),
+            config,
+            sessions,
             notify_tx,
         }
     }
 
+    /// Sends a resumed client the current state of its lobby
+    /// and the chat it may have missed
+    pub async fn resume(&self, id: &TcpId) -> anyhow::Result<()> {
+        let lobbies = self.lobbies.lock().await;
+        self.notify_snapshot(&lobbies, id).await?;
+        self.notify_chat_history(&lobbies, id).await
+    }
+
     async fn listen_for_udp_addresses(&self) -> anyhow::Result<()> {
-        let socket = UdpSocket::bind("127.0.0.1:4000").await?;
+        let socket = UdpSocket::bind((self.config.udp_bind.as_str(), self.config.udp_port)).await?;
         loop {
-            let mut buf: TcpId = [0; 32];
+            let mut buf: state::UdpToken = [0; 32];
             let Ok((size, addr)) = socket.recv_from(&mut buf).await else {
                 continue;
             };
             if size != 32 {
-                println!("udp size not 32");
+                log::warn!("udp size not 32");
                 continue;
             }
-            let SocketAddr::V4(v4) = addr else {
-                unreachable!();
-            };
-            println!("got udp message: {buf:?}, addr: {addr}");
-            let lobby_id = self.lobbies.lock().await.set_client_udp_address(buf, v4);
-            if let Some(lobby_id) = lobby_id {
-                self.notify_lobby(&lobby_id).await?;
+            // v4 peers show up as mapped addresses on a dual stack socket
+            let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+            log::debug!("got udp token from {addr}");
+            let mut lobbies = self.lobbies.lock().await;
+            if let Some((lobby_id, delta)) = lobbies.set_client_udp_address(&buf, addr) {
+                self.notify_delta(&lobbies, &lobby_id, delta).await?;
             } else {
-                println!("warn: udp connection init without lobby");
+                log::warn!("udp token of no lobby member from {addr}");
             }
         }
     }
@@ -296,27 +1196,80 @@ impl RpcServer {
     }
 
     async fn cleanup(&self, id: &TcpId) -> anyhow::Result<()> {
+        self.relay.release(id);
         self.cleanup_lobbies(id).await?;
         Ok(())
     }
 
     async fn cleanup_lobbies(&self, id: &TcpId) -> anyhow::Result<()> {
-        let lobby_id = {
-            let mut lobbies = self.lobbies.lock().await;
-            let Some(lobby_id) = lobbies.get_tcp_id_lobby(id).map(|v| v.id.clone()) else {
-                return Ok(());
-            };
-            lobbies.cleanup(&id);
-            lobby_id
+        let mut lobbies = self.lobbies.lock().await;
+        if let Some((lobby_id, deltas)) = lobbies.cleanup(id) {
+            for delta in deltas {
+                self.notify_delta(&lobbies, &lobby_id, delta).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells the lobby `id` switched away from that it left, like a disconnect would
+    async fn notify_left(
+        &self,
+        lobbies: &state::Lobbies,
+        id: &TcpId,
+        left: Option<String>,
+        deltas: Vec<state::LobbyDelta>,
+    ) -> anyhow::Result<()> {
+        let Some(lobby_id) = left else {
+            return Ok(());
         };
-        self.notify_lobby(&lobby_id).await?;
+        // its permissions were for the old lobby
+        self.relay.release(id);
+        for delta in deltas {
+            self.notify_delta(lobbies, &lobby_id, delta).await?;
+        }
         Ok(())
     }
 
-    async fn notify_lobby(&self, lobby_id: &str) -> anyhow::Result<()> {
-        println!("notifying lobby \"{lobby_id}\"");
+    /// Sends `delta` to every client of the lobby. Takes the locked lobbies
+    /// so deltas are queued in the order they were numbered
+    async fn notify_delta(
+        &self,
+        lobbies: &state::Lobbies,
+        lobby_id: &str,
+        delta: state::LobbyDelta,
+    ) -> anyhow::Result<()> {
+        log::debug!("notifying lobby \"{lobby_id}\" of {}", delta.seq);
+        let Some(lobby) = lobbies.get(lobby_id) else {
+            return Ok(());
+        };
+        // every lobby change passes through here, so the relay follows who is in it
+        for client in lobby.clients.iter().filter(|v| v.relay_port.is_some()) {
+            self.relay
+                .set_permissions(&client.tcp_id, lobby.peer_ips(&client.id));
+        }
 
-        let lobbies = self.lobbies.lock().await;
+        let notify_lobby = lobby
+            .clients
+            .iter()
+            .map(|client| NotifyLobby {
+                tcp_id: client.tcp_id,
+                notification: Notification::LobbyDelta(delta.for_client(lobby, &client.id)),
+            })
+            .collect::<Vec<_>>();
+        self.notify_tx
+            .clone()
+            .send(Notify::Lobby(notify_lobby))
+            .await?;
+        Ok(())
+    }
+
+    /// Sends `notification` to every client of the lobby
+    async fn notify_lobby(
+        &self,
+        lobbies: &state::Lobbies,
+        lobby_id: &str,
+        notification: Notification,
+    ) -> anyhow::Result<()> {
         let Some(lobby) = lobbies.get(lobby_id) else {
             return Ok(());
         };
@@ -325,8 +1278,8 @@ impl RpcServer {
             .clients
             .iter()
             .map(|client| NotifyLobby {
-                tcp_id: client.id,
-                lobby_info: state::LobbyInfoData::from_lobby(lobby).excluding_client(client.id),
+                tcp_id: client.tcp_id,
+                notification: notification.clone(),
             })
             .collect::<Vec<_>>();
 
@@ -337,47 +1290,204 @@ impl RpcServer {
         Ok(())
     }
 
-    pub fn get_handler(&self, id: TcpId, connection: RpcConn<TcpStream>) -> RpcServerHandler {
-        RpcServerHandler {
-            server: self.clone(),
-            id,
-            connection,
+    /// Replays the chat of the client's lobby, if there is any
+    async fn notify_chat_history(
+        &self,
+        lobbies: &state::Lobbies,
+        id: &TcpId,
+    ) -> anyhow::Result<()> {
+        let Some(lobby) = lobbies.get_tcp_id_lobby(id) else {
+            return Ok(());
+        };
+        if lobby.chat.is_empty() {
+            return Ok(());
         }
+        let history = lobby.chat.iter().cloned().collect();
+        self.notify_client(id, Notification::ChatHistory(history))
+            .await
     }
-}
 
-#[derive(Debug)]
-pub struct RpcNotifyClient {
-    connection: RpcConn<TcpStream>,
-}
+    /// Sends the client a snapshot of its lobby, deltas after it follow in order
+    async fn notify_snapshot(&self, lobbies: &state::Lobbies, id: &TcpId) -> anyhow::Result<()> {
+        let Some(lobby) = lobbies.get_tcp_id_lobby(id) else {
+            return Ok(());
+        };
+        let notification = Notification::LobbyInfo(state::LobbyInfoData::for_client(
+            lobby,
+            state::member_id(id),
+        ));
+        self.notify_client(id, notification).await
+    }
+
+    /// Like [`Self::notify_delta`], also telling the client that was
+    /// removed, which is no longer part of the lobby
+    async fn notify_removed(
+        &self,
+        lobbies: &state::Lobbies,
+        lobby_id: &str,
+        removed: &TcpId,
+        delta: state::LobbyDelta,
+    ) -> anyhow::Result<()> {
+        self.notify_client(removed, Notification::LobbyDelta(delta.clone()))
+            .await?;
+        self.notify_delta(lobbies, lobby_id, delta).await
+    }
 
-impl RpcNotifyClient {
-    pub fn new(connection: RpcConn<TcpStream>) -> Self {
-        Self { connection }
+    async fn notify_client(&self, id: &TcpId, notification: Notification) -> anyhow::Result<()> {
+        let notify_lobby = NotifyLobby {
+            tcp_id: *id,
+            notification,
+        };
+        self.notify_tx
+            .clone()
+            .send(Notify::Lobby(vec![notify_lobby]))
+            .await?;
+        Ok(())
     }
 
-    async fn send_lobby_info(&mut self, data: state::LobbyInfoData) -> anyhow::Result<VoidRet> {
-        Ok(self
-            .connection
-            .call(RpcNotifyCode::LobbyInfo as u32, data)
-            .await?)
+    pub fn get_handler(
+        &self,
+        id: TcpId,
+        addr: IpAddr,
+        connection: RpcConn<MuxChannel>,
+    ) -> RpcServerHandler {
+        RpcServerHandler {
+            server: self.clone(),
+            id,
+            addr,
+            connection: Some(connection),
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct NotifyLobby {
     tcp_id: TcpId,
-    lobby_info: state::LobbyInfoData,
+    notification: Notification,
 }
 
 #[derive(Debug)]
 pub enum Notify {
     Lobby(Vec<NotifyLobby>),
-    NewReceiver(TcpId, RpcNotifyClient),
+    /// The channel is shut down once the receiver is gone,
+    /// taking the rest of its connection with it
+    NewReceiver(TcpId, RpcNotifyClient, MuxChannel),
+}
+
+/// Most notifications waiting for a single receiver before it is dropped
+const NOTIFY_QUEUE_LEN: usize = 64;
+/// Longest a receiver may have undelivered notifications before it is dropped
+const NOTIFY_MAX_LAG: time::Duration = time::Duration::from_secs(10);
+
+impl Notification {
+    /// Whether sending `self` makes `older` pointless
+    fn supersedes(&self, older: &Self) -> bool {
+        match (self, older) {
+            (Self::LobbyInfo(_), Self::LobbyInfo(_)) => true,
+            // the snapshot already contains the delta
+            (Self::LobbyInfo(new), Self::LobbyDelta(old)) => old.seq <= new.seq,
+            (Self::ChatHistory(_), Self::ChatHistory(_)) => true,
+            (
+                Self::LobbyInfo(_) | Self::LobbyDelta(_) | Self::Chat(_) | Self::ChatHistory(_),
+                _,
+            ) => false,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct NotifyQueue {
+    messages: VecDeque<Notification>,
+    /// When the receiver last had nothing left to deliver
+    behind_since: Option<time::Instant>,
+}
+
+/// Outbound side of one receiver, drained by its own writer task
+/// so a slow client only holds up itself
+struct NotifyReceiver {
+    queue: Arc<Mutex<NotifyQueue>>,
+    wake_tx: mpsc::Sender<()>,
+    writer: smol::Task<()>,
+    /// Also held by the writer, tells when heartbeats stopped
+    client: RpcNotifyClient,
+    connection: MuxChannel,
+}
+
+impl NotifyReceiver {
+    fn new(client: RpcNotifyClient, connection: MuxChannel) -> Self {
+        let queue = Arc::new(Mutex::new(NotifyQueue::default()));
+        let (wake_tx, wake_rx) = mpsc::channel(1);
+        Self {
+            writer: smol::spawn(Self::write(client.clone(), queue.clone(), wake_rx)),
+            queue,
+            wake_tx,
+            client,
+            connection,
+        }
+    }
+
+    /// Queues `message` in place of the stale ones it replaces,
+    /// returns false if the queue is full
+    fn push(&mut self, message: Notification) -> bool {
+        {
+            let mut queue = self.queue.lock().unwrap();
+            queue.messages.retain(|v| !message.supersedes(v));
+            if queue.messages.len() >= NOTIFY_QUEUE_LEN {
+                return false;
+            }
+            queue.messages.push_back(message);
+            queue.behind_since.get_or_insert_with(time::Instant::now);
+        }
+        // a full channel already holds a wakeup
+        let _ = self.wake_tx.try_send(());
+        true
+    }
+
+    fn is_alive(&self) -> bool {
+        let lagging = self
+            .queue
+            .lock()
+            .unwrap()
+            .behind_since
+            .is_some_and(|v| v.elapsed() > NOTIFY_MAX_LAG);
+        !lagging && !self.writer.is_finished() && !self.client.is_closed()
+    }
+
+    async fn write(
+        client: RpcNotifyClient,
+        queue: Arc<Mutex<NotifyQueue>>,
+        mut wake_rx: mpsc::Receiver<()>,
+    ) {
+        while wake_rx.next().await.is_some() {
+            loop {
+                // own statement, a guard in the match scrutinee would live across the await
+                let message = queue.lock().unwrap().messages.pop_front();
+                let Some(message) = message else {
+                    break;
+                };
+                let ret = match message {
+                    Notification::LobbyInfo(v) => client.lobby_info(v).await,
+                    Notification::LobbyDelta(v) => client.lobby_delta(v).await,
+                    Notification::Chat(v) => client.chat(v).await,
+                    Notification::ChatHistory(v) => client.chat_history(v).await,
+                };
+                match ret {
+                    Ok(_) => {}
+                    Err(CallError::Rpc(e)) => log::debug!("notify failed: {}", e),
+                    Err(CallError::Connection(_)) => return,
+                }
+
+                let mut queue = queue.lock().unwrap();
+                if queue.messages.is_empty() {
+                    queue.behind_since = None;
+                }
+            }
+        }
+    }
 }
 
 pub struct Notifier {
-    receivers: HashMap<TcpId, RefCell<Option<RpcNotifyClient>>>,
+    receivers: HashMap<TcpId, NotifyReceiver>,
     notify_rx: mpsc::Receiver<Notify>,
 }
 
@@ -389,35 +1499,27 @@ impl Notifier {
         }
     }
 
-    async fn notify_lobby(&self, lobbies: Vec<NotifyLobby>) -> anyhow::Result<()> {
-        let futures = lobbies
-            .iter()
-            .map(|v| async {
-                let Some(receiver) = self.receivers.get(&v.tcp_id) else {
-                    return Result::<(), anyhow::Error>::Ok(());
-                };
-                let mut should_remove = false;
-                if let Some(receiver) = receiver.borrow_mut().as_mut() {
-                    should_remove = receiver
-                        .send_lobby_info(v.lobby_info.clone())
-                        .await
-                        .is_err();
-                }
-                if should_remove {
-                    *receiver.borrow_mut() = None;
-                }
-                Ok(())
-            })
-            .collect::<Vec<_>>();
-
-        for fut in futures::future::join_all(futures).await {
-            fut?;
+    fn notify_lobby(&mut self, lobbies: Vec<NotifyLobby>) {
+        for v in lobbies {
+            let Some(receiver) = self.receivers.get_mut(&v.tcp_id) else {
+                continue;
+            };
+            if !receiver.push(v.notification) {
+                log::info!("dropping notify receiver with a full queue");
+                receiver.connection.shutdown();
+                self.receivers.remove(&v.tcp_id);
+            }
         }
-        Ok(())
     }
 
     fn cleanup(&mut self) {
-        self.receivers.retain(|_, v| v.borrow().is_some());
+        self.receivers.retain(|_, v| {
+            let alive = v.is_alive();
+            if !alive {
+                v.connection.shutdown();
+            }
+            alive
+        });
     }
 
     pub async fn listen(mut self) -> anyhow::Result<()> {
@@ -427,9 +1529,13 @@ impl Notifier {
         loop {
             futures::select_biased! {
                 notify_res = self.notify_rx.recv() => match notify_res? {
-                    Notify::Lobby(v) => self.notify_lobby(v).await?,
-                    Notify::NewReceiver(id, v) => {
-                        self.receivers.insert(id, RefCell::new(Some(v)));
+                    Notify::Lobby(v) => self.notify_lobby(v),
+                    Notify::NewReceiver(id, client, connection) => {
+                        let receiver = NotifyReceiver::new(client, connection);
+                        // a resumed session replaces a connection that may be half open
+                        if let Some(old) = self.receivers.insert(id, receiver) {
+                            old.connection.shutdown();
+                        }
                     }
                 },
                 _ = cleanup_timer => {
@@ -440,3 +1546,173 @@ impl Notifier {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Adds a receiver for `id`. Returns the server's rpc channel, which keeps
+    /// the connection up, and the client's end of the notify channel
+    async fn receiver(notifier: &mut Notifier, id: TcpId) -> (MuxChannel, MuxChannel) {
+        let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) =
+            futures::future::join(smol::net::TcpStream::connect(addr), listener.accept()).await;
+        let (server, client) = (server.unwrap().0, client.unwrap());
+        // each frame is a few small writes, don't wait on delayed acks between them
+        server.set_nodelay(true).unwrap();
+        client.set_nodelay(true).unwrap();
+        let (server_rpc, server_notify) = crate::mux::mux(server);
+        let (_, client_notify) = crate::mux::mux(client);
+
+        let client = RpcNotifyClient::new(RpcConn::new(server_notify.clone(), Codec::Json));
+        notifier
+            .receivers
+            .insert(id, NotifyReceiver::new(client, server_notify));
+        (server_rpc, client_notify)
+    }
+
+    fn notifier() -> Notifier {
+        Notifier::new(mpsc::channel(1).1)
+    }
+
+    fn chat(id: u64) -> Notification {
+        Notification::Chat(state::ChatMessage {
+            id,
+            sent_at: 0,
+            from: [0; 16],
+            display_name: String::new(),
+            text: String::new(),
+        })
+    }
+
+    fn delta(seq: u64) -> Notification {
+        Notification::LobbyDelta(state::LobbyDelta {
+            seq,
+            event: state::LobbyEvent::HostChanged([0; 16]),
+        })
+    }
+
+    fn info(seq: u64) -> Notification {
+        Notification::LobbyInfo(state::LobbyInfoData::new(
+            seq,
+            [0; 16],
+            state::LobbyLimits::default(),
+            Vec::new(),
+        ))
+    }
+
+    fn push(notifier: &mut Notifier, ids: &[TcpId], notification: Notification) {
+        let lobby = ids
+            .iter()
+            .map(|id| NotifyLobby {
+                tcp_id: *id,
+                notification: notification.clone(),
+            })
+            .collect();
+        notifier.notify_lobby(lobby);
+    }
+
+    fn push_one(notifier: &mut Notifier, notification: Notification) {
+        push(notifier, &[[1; 32]], notification);
+    }
+
+    /// Whether the client's end saw the connection close
+    async fn closed(mut channel: MuxChannel) -> bool {
+        let mut buf = [0; 4096];
+        let read = async {
+            // frames already sent come first
+            while channel.read(&mut buf).await.unwrap() != 0 {}
+            true
+        };
+        let timeout = async {
+            smol::Timer::after(time::Duration::from_secs(2)).await;
+            false
+        };
+        smol::future::or(read, timeout).await
+    }
+
+    #[test]
+    fn stalled_receiver_doesnt_hold_up_the_others() {
+        smol::block_on(async {
+            let mut notifier = notifier();
+            let (draining, stalled) = ([1; 32], [2; 32]);
+            let (_rpc, notify) = receiver(&mut notifier, draining).await;
+            let mut notifications =
+                std::pin::pin!(rpc_user_notify_stream(RpcConn::new(notify, Codec::Json)));
+            let (_stalled_rpc, stalled_notify) = receiver(&mut notifier, stalled).await;
+
+            for id in 1..=NOTIFY_QUEUE_LEN as u64 + 2 {
+                push(&mut notifier, &[draining, stalled], chat(id));
+                let Notification::Chat(v) = notifications.next().await.unwrap().unwrap() else {
+                    unreachable!();
+                };
+                assert_eq!(v.id, id);
+                // one call in flight and a full queue
+                if id == NOTIFY_QUEUE_LEN as u64 {
+                    assert!(notifier.receivers.contains_key(&stalled));
+                }
+            }
+
+            assert!(!notifier.receivers.contains_key(&stalled));
+            assert!(closed(stalled_notify).await);
+        });
+    }
+
+    #[test]
+    fn lagging_receiver_is_dropped() {
+        smol::block_on(async {
+            let mut notifier = notifier();
+            let (_rpc, notify) = receiver(&mut notifier, [1; 32]).await;
+            push_one(&mut notifier, chat(1));
+
+            notifier.cleanup();
+            assert!(notifier.receivers.contains_key(&[1; 32]));
+
+            {
+                let mut queue = notifier.receivers[&[1; 32]].queue.lock().unwrap();
+                queue.behind_since = queue
+                    .behind_since
+                    .map(|v| v - NOTIFY_MAX_LAG - time::Duration::from_secs(1));
+            }
+            notifier.cleanup();
+            assert!(!notifier.receivers.contains_key(&[1; 32]));
+            assert!(closed(notify).await);
+        });
+    }
+
+    #[test]
+    fn snapshot_replaces_the_updates_it_contains() {
+        smol::block_on(async {
+            let mut notifier = notifier();
+            let (_rpc, _notify) = receiver(&mut notifier, [1; 32]).await;
+            // never answered, the rest stays queued
+            push_one(&mut notifier, chat(1));
+            while !notifier.receivers[&[1; 32]]
+                .queue
+                .lock()
+                .unwrap()
+                .messages
+                .is_empty()
+            {
+                smol::Timer::after(time::Duration::from_millis(10)).await;
+            }
+
+            for notification in [delta(1), chat(2), delta(2), info(2), delta(3), info(3)] {
+                push_one(&mut notifier, notification);
+            }
+            let queue = notifier.receivers[&[1; 32]].queue.lock().unwrap();
+            let queued = queue
+                .messages
+                .iter()
+                .map(|v| match v {
+                    Notification::LobbyInfo(v) => format!("info {}", v.seq),
+                    Notification::LobbyDelta(v) => format!("delta {}", v.seq),
+                    Notification::Chat(v) => format!("chat {}", v.id),
+                    Notification::ChatHistory(_) => "history".to_string(),
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(queued, ["chat 2", "info 3"]);
+        });
+    }
+}
@@ -0,0 +1,114 @@
+/// Declares one direction of rpc calls.
+///
+/// Generates the code enum, a typed client and a handler trait whose
+/// `dispatch` decodes a request and routes it to the matching method:
+///
+/// ```ignore
+/// rpc_service! {
+///     /// Calls from the app to the server
+///     service RpcCode, RpcUserClient, RpcUserHandler {
+///         1 => JoinLobby, join_lobby(JoinLobbyData) -> JoinLobbyRet, max 1024;
+///     }
+/// }
+/// ```
+///
+/// `max` is the largest accepted payload for that call, in bytes.
+/// Code `0` is reserved for unknown calls and [`PING_CODE`](crate::rpc::PING_CODE)
+/// for heartbeats, which are answered with an empty response
+macro_rules! rpc_service {
+    (
+        $(#[$meta:meta])*
+        service $code:ident, $client:ident, $handler:ident {
+            $(
+                $(#[$call_meta:meta])*
+                $value:literal => $variant:ident, $method:ident($data:ty) -> $ret:ty, max $max:expr;
+            )*
+        }
+    ) => {
+        #[repr(u32)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum $code {
+            Unknown = 0,
+            $($variant = $value,)*
+            Ping = $crate::rpc::PING_CODE,
+        }
+
+        impl From<u32> for $code {
+            fn from(value: u32) -> Self {
+                match value {
+                    $($value => Self::$variant,)*
+                    $crate::rpc::PING_CODE => Self::Ping,
+                    _ => Self::Unknown,
+                }
+            }
+        }
+
+        impl $crate::rpc::CallCode for $code {
+            fn max_payload_len(self) -> usize {
+                match self {
+                    Self::Unknown | Self::Ping => 0,
+                    $(Self::$variant => $max,)*
+                }
+            }
+        }
+
+        $(#[$meta])*
+        #[derive(Debug, Clone)]
+        pub struct $client {
+            connection: $crate::rpc::RpcCaller<$crate::mux::MuxChannel>,
+        }
+
+        impl $client {
+            pub fn new(connection: $crate::rpc::RpcConn<$crate::mux::MuxChannel>) -> Self {
+                Self {
+                    connection: $crate::rpc::RpcCaller::new(connection),
+                }
+            }
+
+            $(
+                $(#[$call_meta])*
+                pub async fn $method(&self, data: $data) -> Result<$ret, $crate::rpc::CallError> {
+                    self.connection.call($code::$variant as u32, data).await
+                }
+            )*
+
+            /// Whether the connection closed or stopped answering heartbeats
+            pub fn is_closed(&self) -> bool {
+                self.connection.is_closed()
+            }
+        }
+
+        $(#[$meta])*
+        pub trait $handler {
+            $(
+                $(#[$call_meta])*
+                fn $method(
+                    &self,
+                    data: $data,
+                ) -> impl Future<Output = Result<$ret, $crate::rpc::RpcError>>;
+            )*
+
+            /// Decodes the request, runs the matching method and encodes what it returned
+            fn dispatch(
+                &self,
+                codec: $crate::codec::Codec,
+                request: $crate::rpc::Request<$code>,
+            ) -> impl Future<Output = Result<Vec<u8>, $crate::rpc::RpcError>> {
+                async move {
+                    match request.code {
+                        $code::Unknown => Err($crate::rpc::RpcError::new(
+                            $crate::rpc::RpcErrorCode::UnknownCode,
+                            "unknown code",
+                        )),
+                        $code::Ping => Ok(Vec::new()),
+                        $($code::$variant => {
+                            $crate::rpc::call_handler(codec, request.data, |v| self.$method(v)).await
+                        })*
+                    }
+                }
+            }
+        }
+    };
+}
+
+pub(crate) use rpc_service;
@@ -0,0 +1,108 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time,
+};
+
+use crate::conn::{TcpId, rand_bytes};
+
+/// Secret a client presents to take over its session after reconnecting
+pub type ResumeToken = [u8; 32];
+
+#[derive(Debug)]
+struct Session {
+    token: ResumeToken,
+    connections: usize,
+    detached_at: Option<time::Instant>,
+}
+
+/// Sessions outlive their tcp connection for a grace period,
+/// so a client that reconnects in time keeps its lobby state
+#[derive(Debug, Clone)]
+pub struct Sessions {
+    grace: time::Duration,
+    map: Arc<Mutex<HashMap<TcpId, Session>>>,
+}
+
+fn tokens_eq(a: &ResumeToken, b: &ResumeToken) -> bool {
+    // don't leak how much of the token matched
+    a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+impl Sessions {
+    pub fn new(grace: time::Duration) -> Self {
+        Self {
+            grace,
+            map: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn grace(&self) -> time::Duration {
+        self.grace
+    }
+
+    /// Starts a session with one connection attached
+    pub fn create(&self) -> io::Result<(TcpId, ResumeToken)> {
+        let mut id: TcpId = [0; 32];
+        rand_bytes(&mut id)?;
+        let mut token: ResumeToken = [0; 32];
+        rand_bytes(&mut token)?;
+
+        self.map.lock().unwrap().insert(
+            id,
+            Session {
+                token,
+                connections: 1,
+                detached_at: None,
+            },
+        );
+        Ok((id, token))
+    }
+
+    /// Attaches another connection if the session is still around
+    /// and the token matches
+    pub fn resume(&self, id: &TcpId, token: &ResumeToken) -> bool {
+        let mut map = self.map.lock().unwrap();
+        let Some(session) = map.get_mut(id) else {
+            return false;
+        };
+        if !tokens_eq(&session.token, token) {
+            return false;
+        }
+        session.connections += 1;
+        session.detached_at = None;
+        true
+    }
+
+    /// Called when a connection of the session closes
+    pub fn detach(&self, id: &TcpId) {
+        let mut map = self.map.lock().unwrap();
+        let Some(session) = map.get_mut(id) else {
+            return;
+        };
+        session.connections = session.connections.saturating_sub(1);
+        if session.connections == 0 {
+            session.detached_at = Some(time::Instant::now());
+        }
+    }
+
+    /// Removes the session right away, it can't be resumed anymore
+    pub fn end(&self, id: &TcpId) {
+        self.map.lock().unwrap().remove(id);
+    }
+
+    /// Removes the session if nothing resumed it during the grace period,
+    /// returns whether it was removed
+    pub fn expire(&self, id: &TcpId) -> bool {
+        let mut map = self.map.lock().unwrap();
+        let expired = map
+            .get(id)
+            .and_then(|v| v.detached_at)
+            .is_some_and(|v| v.elapsed() >= self.grace);
+        if expired {
+            map.remove(id);
+        }
+        expired
+    }
+}
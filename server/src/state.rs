@@ -1,37 +1,438 @@
-use std::{collections::HashMap, net::SocketAddrV4};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    time,
+};
 
 use serde::{Deserialize, Serialize};
 
-use crate::conn::TcpId;
+use crate::{
+    auth::{InviteKey, PasswordHash},
+    conn::{TcpId, rand_bytes},
+    rpc::{RpcError, RpcErrorCode},
+};
+
+/// Longest an invite token can stay valid
+pub const MAX_INVITE_TTL: time::Duration = time::Duration::from_secs(7 * 24 * 60 * 60);
+
+/// What a client presents to join a protected lobby,
+/// a password also protects a lobby that is created by the join
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Credentials {
+    pub password: Option<String>,
+    pub invite: Option<String>,
+}
+
+/// How many clients a lobby takes.
+/// The server's limits are the default for new lobbies and the most a host can allow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LobbyLimits {
+    pub max_members: usize,
+    /// Clients streaming at the same time, each one adds to every streamer's uplink
+    pub max_streamers: usize,
+}
+
+impl Default for LobbyLimits {
+    fn default() -> Self {
+        Self {
+            max_members: 16,
+            max_streamers: 4,
+        }
+    }
+}
+
+const ID_ADJECTIVES: [&str; 16] = [
+    "amber", "brave", "calm", "dusty", "eager", "fuzzy", "gentle", "happy", "icy", "jolly",
+    "lucky", "misty", "noble", "quiet", "rapid", "sunny",
+];
+const ID_NOUNS: [&str; 16] = [
+    "otter", "falcon", "maple", "comet", "badger", "harbor", "lantern", "meadow", "pebble",
+    "raven", "river", "cedar", "tiger", "walrus", "willow", "zebra",
+];
+
+/// Random bytes after the words, so unlisted lobbies can't be found by guessing ids
+const ID_SUFFIX_LEN: usize = 4;
+/// Fresh ids tried before giving up, collisions are already unlikely after the first
+const ID_ATTEMPTS: usize = 8;
+
+/// Random id like `sunny-otter-3fa9c2d1`, easy to read out to someone
+fn friendly_id() -> std::io::Result<String> {
+    let mut buf = [0; 2 + ID_SUFFIX_LEN];
+    rand_bytes(&mut buf)?;
+    let suffix: String = buf[2..].iter().map(|v| format!("{v:02x}")).collect();
+    Ok(format!(
+        "{}-{}-{}",
+        ID_ADJECTIVES[buf[0] as usize % ID_ADJECTIVES.len()],
+        ID_NOUNS[buf[1] as usize % ID_NOUNS.len()],
+        suffix
+    ))
+}
+
+/// What joining a lobby changed
+#[derive(Debug)]
+pub struct Joined {
+    /// Lobby the client was in before, left for this one
+    pub left: Option<String>,
+    /// Changes to the left lobby, none if it closed
+    pub left_deltas: Vec<LobbyDelta>,
+    pub delta: LobbyDelta,
+    /// What the client sends to the server's udp port to register its address
+    pub udp_token: UdpToken,
+}
+
+/// What the lobby directory shows of a public lobby
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicLobby {
+    pub id: String,
+    pub host_name: String,
+    pub members: usize,
+    pub streamers: usize,
+    pub limits: LobbyLimits,
+    /// Joining needs a password
+    pub locked: bool,
+}
+
+/// Chat messages a lobby keeps to replay to clients that join later
+pub const CHAT_HISTORY_LEN: usize = 50;
+/// Longest chat message, in characters
+pub const MAX_CHAT_MESSAGE_LEN: usize = 500;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    /// Counts up from 1 in each lobby
+    pub id: u64,
+    /// Unix milliseconds, as seen by the server
+    pub sent_at: u64,
+    pub from: MemberId,
+    /// Name of the sender when it was sent, it may have left since
+    pub display_name: String,
+    pub text: String,
+}
+
+/// Longest display name, in characters
+pub const MAX_DISPLAY_NAME_LEN: usize = 32;
+
+/// How a client shows up to the rest of its lobby
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub display_name: String,
+    /// `0xRRGGBB`
+    pub color: u32,
+    /// Hex sha256 of the avatar image, clients fetch and check it themselves
+    pub avatar_hash: Option<String>,
+}
+
+impl Profile {
+    /// Trims the display name and rejects anything other clients can't render
+    pub fn validated(mut self) -> Result<Self, RpcError> {
+        let invalid = |message: &str| Err(RpcError::new(RpcErrorCode::InvalidRequest, message));
+
+        self.display_name = self.display_name.trim().to_string();
+        if self.display_name.is_empty() {
+            return invalid("display name is empty");
+        }
+        if self.display_name.chars().count() > MAX_DISPLAY_NAME_LEN {
+            return invalid("display name is too long");
+        }
+        if self.display_name.chars().any(char::is_control) {
+            return invalid("display name has control characters");
+        }
+        if self.color > 0xffffff {
+            return invalid("color is not 0xRRGGBB");
+        }
+        if let Some(hash) = &self.avatar_hash
+            && (hash.len() != 64 || !hash.chars().all(|v| v.is_ascii_hexdigit()))
+        {
+            return invalid("avatar hash is not a hex sha256");
+        }
+        Ok(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamEndReason {
+    /// The streamer stopped it
+    Stopped,
+    /// The host stopped it
+    StoppedByHost,
+    /// The streamer's pipeline broke
+    Failed,
+}
+
+/// Where the stream of a client is, viewers only get video while it's live
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamState {
+    /// Hasn't streamed since it joined
+    #[default]
+    Idle,
+    /// Announced, the first frames are on their way
+    Starting,
+    Live,
+    Paused,
+    Ended(StreamEndReason),
+}
+
+impl StreamState {
+    /// Counts towards the streamer limit
+    pub fn is_active(self) -> bool {
+        matches!(self, Self::Starting | Self::Live | Self::Paused)
+    }
+
+    /// State after the streamer reports `update`, `None` if the stream isn't running
+    pub fn apply(self, update: StreamUpdate) -> Option<Self> {
+        if !self.is_active() {
+            return None;
+        }
+        Some(match update {
+            StreamUpdate::Live => Self::Live,
+            StreamUpdate::Paused => Self::Paused,
+            StreamUpdate::Failed => Self::Ended(StreamEndReason::Failed),
+        })
+    }
+}
+
+/// What a streamer reports about its own running stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamUpdate {
+    /// Sending video, also ends a pause
+    Live,
+    Paused,
+    Failed,
+}
+
+/// Longest stream title, in characters
+pub const MAX_STREAM_TITLE_LEN: usize = 64;
+
+/// Video codecs viewers know how to depayload and decode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoCodec {
+    H264,
+    Vp8,
+    Vp9,
+    Av1,
+}
+
+impl VideoCodec {
+    /// `encoding-name` of its rtp payload
+    pub fn encoding_name(self) -> &'static str {
+        match self {
+            Self::H264 => "H264",
+            Self::Vp8 => "VP8",
+            Self::Vp9 => "VP9",
+            Self::Av1 => "AV1",
+        }
+    }
+}
+
+/// What is being captured
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceKind {
+    Monitor,
+    Window,
+}
+
+/// What a streamer publishes about its stream, enough for viewers
+/// to build a receiving pipeline before the first packet arrives
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamMetadata {
+    pub codec: VideoCodec,
+    /// Dynamic rtp payload type, the clock rate is always 90 kHz
+    pub payload_type: u8,
+    pub width: u32,
+    pub height: u32,
+    pub framerate: u32,
+    pub bitrate_kbps: u32,
+    /// Can be empty, viewers fall back to the streamer's name
+    pub title: String,
+    pub source: SourceKind,
+}
+
+impl StreamMetadata {
+    /// Trims the title and rejects values no pipeline could be built for
+    pub fn validated(mut self) -> Result<Self, RpcError> {
+        let invalid = |message: &str| Err(RpcError::new(RpcErrorCode::InvalidRequest, message));
+
+        if !(96..=127).contains(&self.payload_type) {
+            return invalid("payload type is not dynamic");
+        }
+        if !(1..=8192).contains(&self.width) || !(1..=8192).contains(&self.height) {
+            return invalid("resolution is out of range");
+        }
+        if !(1..=240).contains(&self.framerate) {
+            return invalid("framerate is out of range");
+        }
+        if !(1..=100_000).contains(&self.bitrate_kbps) {
+            return invalid("bitrate is out of range");
+        }
+        self.title = self.title.trim().to_string();
+        if self.title.chars().count() > MAX_STREAM_TITLE_LEN {
+            return invalid("stream title is too long");
+        }
+        if self.title.chars().any(char::is_control) {
+            return invalid("stream title has control characters");
+        }
+        Ok(self)
+    }
+}
+
+/// How lobby members refer to each other. Derived from the [`TcpId`],
+/// which only the client and the server know, so a client can tell its own
+pub type MemberId = [u8; 16];
+
+pub fn member_id(tcp_id: &TcpId) -> MemberId {
+    let digest = ring::digest::digest(&ring::digest::SHA256, tcp_id);
+    let mut id = [0; 16];
+    id.copy_from_slice(&digest.as_ref()[..16]);
+    id
+}
+
+/// Secret a client registers its udp address with, only it and the server know it
+pub type UdpToken = [u8; 32];
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LobbyClient {
-    pub udp_addr: Option<SocketAddrV4>,
-    pub is_streaming: bool,
-    pub id: TcpId,
+    pub udp_addr: Option<SocketAddr>,
+    pub stream: StreamState,
+    /// Of the last stream it started, kept after it ends
+    pub stream_metadata: Option<StreamMetadata>,
+    /// Streamers it subscribed to, each of them sends its video here.
+    /// Together these make up the watchers of every stream
+    pub watching: HashSet<MemberId>,
+    /// Port of its allocation on the server's relay, others send to it
+    /// instead of `udp_addr` once it can't be reached directly
+    pub relay_port: Option<u16>,
+    pub id: MemberId,
+    /// Never sent, knowing it would let anyone take over the session
+    #[serde(skip)]
+    pub tcp_id: TcpId,
+    pub profile: Profile,
+}
+
+/// Whether video flows between `a` and `b` in either direction,
+/// only then do they get each other's udp address
+fn exchange_video(clients: &[LobbyClient], a: &MemberId, b: &MemberId) -> bool {
+    let watches = |watcher: &MemberId, streamer: &MemberId| {
+        clients
+            .iter()
+            .any(|v| &v.id == watcher && v.watching.contains(streamer))
+    };
+    watches(a, b) || watches(b, a)
+}
+
+/// Drops every subscription to `streamer`, its stream ended or it left
+fn stop_watching(clients: &mut [LobbyClient], streamer: &MemberId) {
+    for client in clients {
+        client.watching.remove(streamer);
+    }
 }
 
 impl LobbyClient {
-    pub fn new(id: TcpId, udp_addr: Option<SocketAddrV4>, is_streaming: bool) -> Self {
+    pub fn new(
+        tcp_id: TcpId,
+        profile: Profile,
+        udp_addr: Option<SocketAddr>,
+        stream: StreamState,
+    ) -> Self {
         Self {
-            id,
+            id: member_id(&tcp_id),
+            tcp_id,
             udp_addr,
-            is_streaming,
+            stream,
+            stream_metadata: None,
+            watching: HashSet::new(),
+            relay_port: None,
+            profile,
         }
     }
 }
 
+/// A change to a lobby, sent to its clients instead of the whole lobby
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LobbyEvent {
+    ClientJoined(LobbyClient),
+    ClientLeft(MemberId),
+    /// The stream of the client is starting with new metadata
+    StreamStarted {
+        id: MemberId,
+        metadata: StreamMetadata,
+    },
+    StreamChanged {
+        id: MemberId,
+        state: StreamState,
+    },
+    Subscribed {
+        watcher: MemberId,
+        streamer: MemberId,
+    },
+    Unsubscribed {
+        watcher: MemberId,
+        streamer: MemberId,
+    },
+    /// The address only reaches clients that exchange video with `id`
+    UdpAddrChanged {
+        id: MemberId,
+        udp_addr: Option<SocketAddr>,
+    },
+    RelayPortChanged {
+        id: MemberId,
+        relay_port: u16,
+    },
+    HostChanged(MemberId),
+    LimitsChanged(LobbyLimits),
+}
+
+/// Event numbered in the order it happened in its lobby, a client that sees a
+/// gap in `seq` has missed one and should ask for a snapshot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LobbyDelta {
+    pub seq: u64,
+    pub event: LobbyEvent,
+}
+
+impl LobbyDelta {
+    /// What `id` gets sent of the delta, without addresses it has no use for
+    pub fn for_client(&self, lobby: &Lobby, id: &MemberId) -> Self {
+        let mut delta = self.clone();
+        match &mut delta.event {
+            LobbyEvent::ClientJoined(client) if !exchange_video(&lobby.clients, &client.id, id) => {
+                client.udp_addr = None;
+            }
+            LobbyEvent::UdpAddrChanged { id: peer, udp_addr }
+                if !exchange_video(&lobby.clients, peer, id) =>
+            {
+                *udp_addr = None;
+            }
+            _ => {}
+        }
+        delta
+    }
+}
+
 #[derive(Debug)]
 pub struct Lobbies {
     map: HashMap<String, Lobby>,
     tcp_id_to_lobby_id: HashMap<TcpId, String>,
+    /// Session of each lobby member's udp token
+    udp_tokens: HashMap<UdpToken, TcpId>,
+    max_lobbies: usize,
+    limits: LobbyLimits,
 }
 
 impl Lobbies {
-    pub fn new() -> Self {
+    pub fn new(max_lobbies: usize, limits: LobbyLimits) -> Self {
         Self {
             map: HashMap::new(),
             tcp_id_to_lobby_id: HashMap::new(),
+            udp_tokens: HashMap::new(),
+            max_lobbies,
+            limits,
         }
     }
 
@@ -51,38 +452,526 @@ impl Lobbies {
         Some(lobby)
     }
 
-    pub fn set_client_udp_address(&mut self, id: TcpId, address: SocketAddrV4) -> Option<String> {
-        let lobby = self.get_tcp_id_lobby_mut(&id)?;
-        lobby.set_udp_address(id, address);
-        Some(lobby.id.clone())
+    /// Returns the lobby of the client that `token` was issued to and what changed in it
+    pub fn set_client_udp_address(
+        &mut self,
+        token: &UdpToken,
+        address: SocketAddr,
+    ) -> Option<(String, LobbyDelta)> {
+        let tcp_id = *self.udp_tokens.get(token)?;
+        let lobby = self.get_tcp_id_lobby_mut(&tcp_id)?;
+        let id = member_id(&tcp_id);
+        lobby.set_udp_address(&id, address)?;
+        let delta = lobby.push_event(LobbyEvent::UdpAddrChanged {
+            id,
+            udp_addr: Some(address),
+        });
+        Some((lobby.id.clone(), delta))
     }
 
-    pub fn set_client_is_streaming(&mut self, id: &TcpId, is_streaming: bool) -> Option<String> {
+    /// Forgets which lobby the client is in, it was already removed from it
+    fn forget_client(&mut self, tcp_id: &TcpId) {
+        self.tcp_id_to_lobby_id.remove(tcp_id);
+        self.udp_tokens.retain(|_, v| v != tcp_id);
+    }
+
+    /// Returns the lobby of the client and what changed in it,
+    /// `None` if it isn't in a lobby or already uses the port
+    pub fn set_client_relay_port(&mut self, id: &TcpId, port: u16) -> Option<(String, LobbyDelta)> {
         let lobby = self.get_tcp_id_lobby_mut(id)?;
-        lobby.set_is_streaming(id, is_streaming);
-        Some(lobby.id.clone())
+        let id = member_id(id);
+        let client = lobby.get_client_mut(&id)?;
+        if client.relay_port == Some(port) {
+            return None;
+        }
+        client.relay_port = Some(port);
+        let delta = lobby.push_event(LobbyEvent::RelayPortChanged {
+            id,
+            relay_port: port,
+        });
+        Some((lobby.id.clone(), delta))
     }
 
-    pub fn cleanup(&mut self, tcp_id: &TcpId) {
-        let Some(lobby) = self.get_tcp_id_lobby_mut(tcp_id) else {
-            return;
-        };
-        lobby.remove_client(*tcp_id);
-        if lobby.clients.len() == 0 {
-            let lobby_id = lobby.id.clone();
+    /// Moves the stream of the client to the state `change` returns for it,
+    /// `None` means the stream can't make that move. Returns the lobby and what changed in it
+    fn change_client_stream(
+        &mut self,
+        id: &TcpId,
+        change: impl FnOnce(StreamState) -> Option<StreamState>,
+    ) -> Result<(String, LobbyDelta), RpcError> {
+        let lobby = self
+            .get_tcp_id_lobby_mut(id)
+            .ok_or_else(|| RpcError::new(RpcErrorCode::NotInLobby, "stream no lobby"))?;
+        let id = member_id(id);
+        let client = lobby
+            .get_client_mut(&id)
+            .ok_or_else(|| RpcError::new(RpcErrorCode::NotInLobby, "stream no lobby"))?;
+        let state = change(client.stream)
+            .ok_or_else(|| RpcError::new(RpcErrorCode::InvalidRequest, "not streaming"))?;
+        client.stream = state;
+        if !state.is_active() {
+            stop_watching(&mut lobby.clients, &id);
+        }
+        let delta = lobby.push_event(LobbyEvent::StreamChanged { id, state });
+        Ok((lobby.id.clone(), delta))
+    }
+
+    /// Starts or restarts the stream of the client with `metadata`, unless its lobby
+    /// already has as many streamers as it allows
+    pub fn start_stream(
+        &mut self,
+        id: &TcpId,
+        metadata: StreamMetadata,
+    ) -> Result<(String, LobbyDelta), RpcError> {
+        let lobby = self
+            .get_tcp_id_lobby_mut(id)
+            .ok_or_else(|| RpcError::new(RpcErrorCode::NotInLobby, "start stream no lobby"))?;
+        let id = member_id(id);
+        let streamers = lobby
+            .clients
+            .iter()
+            .filter(|v| v.stream.is_active() && v.id != id);
+        if streamers.count() >= lobby.limits.max_streamers {
+            return Err(RpcError::new(
+                RpcErrorCode::TooManyStreamers,
+                format!(
+                    "lobby allows {} streamers at a time",
+                    lobby.limits.max_streamers
+                ),
+            ));
+        }
+        let client = lobby
+            .get_client_mut(&id)
+            .ok_or_else(|| RpcError::new(RpcErrorCode::NotInLobby, "start stream no lobby"))?;
+        client.stream = StreamState::Starting;
+        client.stream_metadata = Some(metadata.clone());
+        let delta = lobby.push_event(LobbyEvent::StreamStarted { id, metadata });
+        Ok((lobby.id.clone(), delta))
+    }
+
+    /// Ends the running stream of the client
+    pub fn stop_stream(&mut self, id: &TcpId) -> Result<(String, LobbyDelta), RpcError> {
+        self.change_client_stream(id, |state| {
+            state
+                .is_active()
+                .then_some(StreamState::Ended(StreamEndReason::Stopped))
+        })
+    }
+
+    /// Applies what the client reported about its running stream
+    pub fn update_stream(
+        &mut self,
+        id: &TcpId,
+        update: StreamUpdate,
+    ) -> Result<(String, LobbyDelta), RpcError> {
+        self.change_client_stream(id, |state| state.apply(update))
+    }
+
+    /// Subscribes `watcher` to the running stream of `streamer` in the same lobby
+    pub fn subscribe(
+        &mut self,
+        watcher: &TcpId,
+        streamer: &MemberId,
+    ) -> Result<(String, LobbyDelta), RpcError> {
+        let lobby = self.get_watched_lobby_mut(watcher, streamer)?;
+        let streaming = lobby
+            .get_client_mut(streamer)
+            .is_some_and(|v| v.stream.is_active());
+        if !streaming {
+            return Err(RpcError::new(RpcErrorCode::InvalidRequest, "not streaming"));
+        }
+        let watcher = member_id(watcher);
+        let client = lobby.get_client_mut(&watcher).unwrap();
+        if !client.watching.insert(*streamer) {
+            return Err(RpcError::new(
+                RpcErrorCode::InvalidRequest,
+                "already watching",
+            ));
+        }
+        let delta = lobby.push_event(LobbyEvent::Subscribed {
+            watcher,
+            streamer: *streamer,
+        });
+        Ok((lobby.id.clone(), delta))
+    }
+
+    /// Stops the video `streamer` sends to `watcher`
+    pub fn unsubscribe(
+        &mut self,
+        watcher: &TcpId,
+        streamer: &MemberId,
+    ) -> Result<(String, LobbyDelta), RpcError> {
+        let lobby = self.get_watched_lobby_mut(watcher, streamer)?;
+        let watcher = member_id(watcher);
+        let client = lobby.get_client_mut(&watcher).unwrap();
+        if !client.watching.remove(streamer) {
+            return Err(RpcError::new(RpcErrorCode::InvalidRequest, "not watching"));
+        }
+        let delta = lobby.push_event(LobbyEvent::Unsubscribed {
+            watcher,
+            streamer: *streamer,
+        });
+        Ok((lobby.id.clone(), delta))
+    }
+
+    fn get_watched_lobby_mut(
+        &mut self,
+        watcher: &TcpId,
+        streamer: &MemberId,
+    ) -> Result<&mut Lobby, RpcError> {
+        if &member_id(watcher) == streamer {
+            return Err(RpcError::new(
+                RpcErrorCode::InvalidRequest,
+                "can't watch your own stream",
+            ));
+        }
+        let lobby = self
+            .get_tcp_id_lobby_mut(watcher)
+            .ok_or_else(|| RpcError::new(RpcErrorCode::NotInLobby, "watch no lobby"))?;
+        if lobby.get_client_mut(streamer).is_none() {
+            return Err(RpcError::new(
+                RpcErrorCode::InvalidRequest,
+                "client is not in the lobby",
+            ));
+        }
+        Ok(lobby)
+    }
+
+    /// Removes the client from its lobby, returns the lobby if anyone is left in it.
+    /// A host that leaves hands the lobby to one of the remaining clients
+    pub fn cleanup(&mut self, tcp_id: &TcpId) -> Option<(String, Vec<LobbyDelta>)> {
+        let lobby = self.get_tcp_id_lobby_mut(tcp_id)?;
+        let id = member_id(tcp_id);
+        lobby.remove_client(&id);
+        let mut deltas = vec![lobby.push_event(LobbyEvent::ClientLeft(id))];
+        let lobby_id = lobby.id.clone();
+        let next_host = lobby.clients.first().map(|v| v.id);
+        self.forget_client(tcp_id);
+
+        let Some(next_host) = next_host else {
             self.map.remove(&lobby_id);
+            return None;
+        };
+        let lobby = self.map.get_mut(&lobby_id).unwrap();
+        if lobby.host == id {
+            lobby.host = next_host;
+            deltas.push(lobby.push_event(LobbyEvent::HostChanged(next_host)));
         }
+        Some((lobby_id, deltas))
     }
 
-    pub fn join(&mut self, id: String, client: LobbyClient) {
-        let mut new_lobby = Lobby::new(id.clone());
-        new_lobby.add_client(client.clone());
+    /// Joins `client`, connected from `addr`, to the lobby of `id`,
+    /// creating it with the client as host if it doesn't exist.
+    /// A client in another lobby leaves that one first
+    pub fn join(
+        &mut self,
+        id: String,
+        client: LobbyClient,
+        addr: IpAddr,
+        credentials: &Credentials,
+    ) -> Result<Joined, RpcError> {
+        match self.map.get(&id) {
+            Some(lobby) => {
+                lobby.check_banned(&client.id, addr)?;
+                lobby.check_access(credentials)?;
+                let is_member = lobby.clients.iter().any(|v| v.id == client.id);
+                if !is_member && lobby.clients.len() >= lobby.limits.max_members {
+                    return Err(RpcError::new(
+                        RpcErrorCode::LobbyFull,
+                        format!("lobby allows {} members", lobby.limits.max_members),
+                    ));
+                }
+            }
+            None if self.map.len() >= self.max_lobbies => {
+                return Err(Self::too_many_lobbies());
+            }
+            None => {
+                let mut lobby =
+                    Lobby::new(id.clone(), client.id, self.limits).map_err(RpcError::internal)?;
+                if let Some(password) = &credentials.password {
+                    lobby.password = Some(PasswordHash::new(password).map_err(RpcError::internal)?);
+                }
+                self.map.insert(id.clone(), lobby);
+            }
+        }
+
+        let mut udp_token = [0; 32];
+        rand_bytes(&mut udp_token).map_err(RpcError::internal)?;
+        let left = self
+            .tcp_id_to_lobby_id
+            .get(&client.tcp_id)
+            .filter(|v| **v != id)
+            .cloned();
+        let left_deltas = match left {
+            Some(_) => self
+                .cleanup(&client.tcp_id)
+                .map(|(_, deltas)| deltas)
+                .unwrap_or_default(),
+            None => Vec::new(),
+        };
+        // a rejoin replaces the token
+        self.forget_client(&client.tcp_id);
+        self.tcp_id_to_lobby_id.insert(client.tcp_id, id.clone());
+        self.udp_tokens.insert(udp_token, client.tcp_id);
+        let lobby = self.map.get_mut(&id).unwrap();
+        lobby.add_client(client.clone());
+        lobby.addrs.insert(client.id, addr);
+        Ok(Joined {
+            left,
+            left_deltas,
+            delta: lobby.push_event(LobbyEvent::ClientJoined(client)),
+            udp_token,
+        })
+    }
+
+    fn too_many_lobbies() -> RpcError {
+        RpcError::new(
+            RpcErrorCode::TooManyLobbies,
+            "too many lobbies on this server",
+        )
+    }
+
+    /// Creates a lobby with a fresh id and `client` as its host, returns the id
+    pub fn create(
+        &mut self,
+        client: LobbyClient,
+        addr: IpAddr,
+        credentials: &Credentials,
+        public: bool,
+    ) -> Result<(String, Joined), RpcError> {
+        if self.map.len() >= self.max_lobbies {
+            return Err(Self::too_many_lobbies());
+        }
+        let mut id = None;
+        for _ in 0..ID_ATTEMPTS {
+            let candidate = friendly_id().map_err(RpcError::internal)?;
+            if !self.map.contains_key(&candidate) {
+                id = Some(candidate);
+                break;
+            }
+        }
+        let id = id.ok_or_else(|| RpcError::internal(anyhow::anyhow!("no free lobby id")))?;
+        let joined = self.join(id.clone(), client, addr, credentials)?;
+        self.map.get_mut(&id).unwrap().public = public;
+        Ok((id, joined))
+    }
+
+    /// Public lobbies that don't need an invite, by id
+    pub fn list_public(&self) -> Vec<PublicLobby> {
+        let mut lobbies = self
+            .map
+            .values()
+            .filter(|v| v.public && !v.invite_only)
+            .map(|v| PublicLobby {
+                id: v.id.clone(),
+                host_name: v
+                    .clients
+                    .iter()
+                    .find(|client| client.id == v.host)
+                    .map(|client| client.profile.display_name.clone())
+                    .unwrap_or_default(),
+                members: v.clients.len(),
+                streamers: v.clients.iter().filter(|v| v.stream.is_active()).count(),
+                limits: v.limits,
+                locked: v.password.is_some(),
+            })
+            .collect::<Vec<_>>();
+        lobbies.sort_by(|a, b| a.id.cmp(&b.id));
+        lobbies
+    }
+
+    /// Lobby that `host` is the host of and that `target` is also in
+    fn get_hosted_lobby_mut(
+        &mut self,
+        host: &TcpId,
+        target: &MemberId,
+    ) -> Result<&mut Lobby, RpcError> {
+        let lobby = self
+            .get_tcp_id_lobby_mut(host)
+            .ok_or_else(|| RpcError::new(RpcErrorCode::NotInLobby, "host action no lobby"))?;
+        lobby.check_host(&member_id(host))?;
+        if lobby.get_client_mut(target).is_none() {
+            return Err(RpcError::new(
+                RpcErrorCode::InvalidRequest,
+                "client is not in the lobby",
+            ));
+        }
+        Ok(lobby)
+    }
+
+    /// Removes `target` from the lobby of `host`, only the host may do that.
+    /// Returns the lobby, the session of `target` and what changed
+    pub fn kick(
+        &mut self,
+        host: &TcpId,
+        target: &MemberId,
+    ) -> Result<(String, TcpId, LobbyDelta), RpcError> {
+        if &member_id(host) == target {
+            return Err(RpcError::new(
+                RpcErrorCode::InvalidRequest,
+                "the host can't kick itself",
+            ));
+        }
+        let lobby = self.get_hosted_lobby_mut(host, target)?;
+        let tcp_id = lobby.get_client_mut(target).unwrap().tcp_id;
+        lobby.remove_client(target);
+        let delta = lobby.push_event(LobbyEvent::ClientLeft(*target));
+        let lobby_id = lobby.id.clone();
+        self.forget_client(&tcp_id);
+        Ok((lobby_id, tcp_id, delta))
+    }
+
+    /// Kicks `target` and keeps its session and address out of the lobby for good
+    pub fn ban(
+        &mut self,
+        host: &TcpId,
+        target: &MemberId,
+    ) -> Result<(String, TcpId, LobbyDelta), RpcError> {
+        let lobby = self.get_hosted_lobby_mut(host, target)?;
+        // banning the address of the host would lock out everyone behind the same nat
+        let addr = lobby
+            .addrs
+            .get(target)
+            .copied()
+            .filter(|v| lobby.addrs.get(&member_id(host)) != Some(v));
+
+        let (lobby_id, tcp_id, delta) = self.kick(host, target)?;
+        let lobby = self.map.get_mut(&lobby_id).unwrap();
+        lobby.banned_ids.insert(*target);
+        lobby.banned_addrs.extend(addr);
+        Ok((lobby_id, tcp_id, delta))
+    }
+
+    /// Stops the stream of `target` for everyone, only the host may do that
+    pub fn stop_client_stream(
+        &mut self,
+        host: &TcpId,
+        target: &MemberId,
+    ) -> Result<(String, LobbyDelta), RpcError> {
+        let target = self
+            .get_hosted_lobby_mut(host, target)?
+            .get_client_mut(target)
+            .unwrap()
+            .tcp_id;
+        self.change_client_stream(&target, |state| {
+            state
+                .is_active()
+                .then_some(StreamState::Ended(StreamEndReason::StoppedByHost))
+        })
+    }
 
-        self.tcp_id_to_lobby_id.insert(client.id, id.clone());
-        self.map
-            .entry(id)
-            .and_modify(|v| v.add_client(client))
-            .or_insert(new_lobby);
+    /// Makes `target` the host, only the current host may do that
+    pub fn transfer_host(
+        &mut self,
+        host: &TcpId,
+        target: &MemberId,
+    ) -> Result<(String, LobbyDelta), RpcError> {
+        let lobby = self.get_hosted_lobby_mut(host, target)?;
+        lobby.host = *target;
+        let delta = lobby.push_event(LobbyEvent::HostChanged(*target));
+        Ok((lobby.id.clone(), delta))
+    }
+
+    /// Adds a message from `id` to the chat of its lobby
+    pub fn send_chat(&mut self, id: &TcpId, text: &str) -> Result<(String, ChatMessage), RpcError> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(RpcError::new(
+                RpcErrorCode::InvalidRequest,
+                "chat message is empty",
+            ));
+        }
+        if text.chars().count() > MAX_CHAT_MESSAGE_LEN {
+            return Err(RpcError::new(
+                RpcErrorCode::InvalidRequest,
+                "chat message is too long",
+            ));
+        }
+
+        let lobby = self
+            .get_tcp_id_lobby_mut(id)
+            .ok_or_else(|| RpcError::new(RpcErrorCode::NotInLobby, "chat no lobby"))?;
+        let from = member_id(id);
+        let display_name = lobby
+            .get_client_mut(&from)
+            .map(|v| v.profile.display_name.clone())
+            .unwrap_or_default();
+        let sent_at = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|v| v.as_millis() as u64)
+            .unwrap_or(0);
+
+        lobby.chat_seq += 1;
+        let message = ChatMessage {
+            id: lobby.chat_seq,
+            sent_at,
+            from,
+            display_name,
+            text: text.to_string(),
+        };
+        if lobby.chat.len() >= CHAT_HISTORY_LEN {
+            lobby.chat.pop_front();
+        }
+        lobby.chat.push_back(message.clone());
+        Ok((lobby.id.clone(), message))
+    }
+
+    /// Changes the limits of the lobby of `id` for new joins and streams,
+    /// only its host may do that. Clients already over them stay
+    pub fn set_limits(
+        &mut self,
+        id: &TcpId,
+        limits: LobbyLimits,
+    ) -> Result<(String, LobbyDelta), RpcError> {
+        let max = self.limits;
+        let lobby = self
+            .get_tcp_id_lobby_mut(id)
+            .ok_or_else(|| RpcError::new(RpcErrorCode::NotInLobby, "set limits no lobby"))?;
+        lobby.check_host(&member_id(id))?;
+        if !(1..=max.max_members).contains(&limits.max_members)
+            || limits.max_streamers > max.max_streamers
+        {
+            return Err(RpcError::new(
+                RpcErrorCode::InvalidRequest,
+                format!(
+                    "limits must be within {} members and {} streamers",
+                    max.max_members, max.max_streamers
+                ),
+            ));
+        }
+        lobby.limits = limits;
+        let delta = lobby.push_event(LobbyEvent::LimitsChanged(limits));
+        Ok((lobby.id.clone(), delta))
+    }
+
+    /// Replaces who may join the lobby of `id`, only its host may do that
+    pub fn set_access(
+        &mut self,
+        id: &TcpId,
+        password: Option<&str>,
+        invite_only: bool,
+    ) -> Result<(), RpcError> {
+        let lobby = self
+            .get_tcp_id_lobby_mut(id)
+            .ok_or_else(|| RpcError::new(RpcErrorCode::NotInLobby, "set access no lobby"))?;
+        lobby.check_host(&member_id(id))?;
+        lobby.password = password
+            .map(PasswordHash::new)
+            .transpose()
+            .map_err(RpcError::internal)?;
+        lobby.invite_only = invite_only;
+        Ok(())
+    }
+
+    /// Token that lets anyone join the lobby of `id` for `ttl`,
+    /// only its host may issue them
+    pub fn create_invite(&self, id: &TcpId, ttl: time::Duration) -> Result<String, RpcError> {
+        let lobby = self
+            .get_tcp_id_lobby(id)
+            .ok_or_else(|| RpcError::new(RpcErrorCode::NotInLobby, "invite no lobby"))?;
+        lobby.check_host(&member_id(id))?;
+        let expires_at = time::SystemTime::now() + ttl.min(MAX_INVITE_TTL);
+        Ok(lobby.invite_key.issue(&lobby.id, expires_at))
     }
 }
 
@@ -90,64 +979,244 @@ impl Lobbies {
 pub struct Lobby {
     pub id: String,
     pub clients: Vec<LobbyClient>,
+    /// Sequence number of the last event
+    pub seq: u64,
+    pub limits: LobbyLimits,
+    /// Latest chat messages, oldest first
+    pub chat: VecDeque<ChatMessage>,
+    /// Id of the last chat message
+    chat_seq: u64,
+    /// Listed in the lobby directory
+    public: bool,
+    /// Starts out as the creator
+    host: MemberId,
+    password: Option<PasswordHash>,
+    /// Only invite tokens let new clients in
+    invite_only: bool,
+    invite_key: InviteKey,
+    /// Where each client connected from when it joined, never sent to clients
+    addrs: HashMap<MemberId, IpAddr>,
+    banned_ids: HashSet<MemberId>,
+    banned_addrs: HashSet<IpAddr>,
 }
 
 impl Lobby {
-    fn new(id: String) -> Self {
-        Self {
+    fn new(id: String, host: MemberId, limits: LobbyLimits) -> std::io::Result<Self> {
+        Ok(Self {
             id,
             clients: vec![],
+            seq: 0,
+            limits,
+            chat: VecDeque::new(),
+            chat_seq: 0,
+            public: false,
+            host,
+            password: None,
+            invite_only: false,
+            invite_key: InviteKey::new()?,
+            addrs: HashMap::new(),
+            banned_ids: HashSet::new(),
+            banned_addrs: HashSet::new(),
+        })
+    }
+
+    pub fn host(&self) -> &MemberId {
+        &self.host
+    }
+
+    /// Addresses the other clients connect and send from
+    pub fn peer_ips(&self, id: &MemberId) -> HashSet<IpAddr> {
+        let tcp_ips = self.addrs.iter().filter(|v| v.0 != id).map(|v| *v.1);
+        let udp_ips = self
+            .clients
+            .iter()
+            .filter(|v| &v.id != id)
+            .filter_map(|v| v.udp_addr)
+            .map(|v| v.ip());
+        tcp_ips.chain(udp_ips).collect()
+    }
+
+    fn check_host(&self, id: &MemberId) -> Result<(), RpcError> {
+        if &self.host != id {
+            return Err(RpcError::new(
+                RpcErrorCode::NotHost,
+                "only the lobby host can do that",
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_banned(&self, id: &MemberId, addr: IpAddr) -> Result<(), RpcError> {
+        if self.banned_ids.contains(id) || self.banned_addrs.contains(&addr) {
+            return Err(RpcError::new(
+                RpcErrorCode::Banned,
+                "banned from this lobby",
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_access(&self, credentials: &Credentials) -> Result<(), RpcError> {
+        if self.password.is_none() && !self.invite_only {
+            return Ok(());
+        }
+        let invite_ok = credentials
+            .invite
+            .as_ref()
+            .is_some_and(|v| self.invite_key.verify(&self.id, v));
+        let password_ok = match (&self.password, &credentials.password) {
+            (Some(hash), Some(password)) => !self.invite_only && hash.verify(password),
+            _ => false,
+        };
+        if invite_ok || password_ok {
+            return Ok(());
+        }
+
+        if credentials.invite.is_none() && credentials.password.is_none() {
+            Err(RpcError::new(
+                RpcErrorCode::CredentialsRequired,
+                "lobby needs a password or an invite",
+            ))
+        } else {
+            Err(RpcError::new(
+                RpcErrorCode::InvalidCredentials,
+                "wrong password or invalid invite",
+            ))
         }
     }
 
-    fn set_udp_address(&mut self, id: TcpId, address: SocketAddrV4) -> Option<()> {
-        let client = self.get_tcp_id_client_mut(&id)?;
+    fn push_event(&mut self, event: LobbyEvent) -> LobbyDelta {
+        self.seq += 1;
+        LobbyDelta {
+            seq: self.seq,
+            event,
+        }
+    }
+
+    fn set_udp_address(&mut self, id: &MemberId, address: SocketAddr) -> Option<()> {
+        let client = self.get_client_mut(id)?;
         client.udp_addr = Some(address);
         Some(())
     }
 
-    fn set_is_streaming(&mut self, id: &TcpId, is_streaming: bool) -> Option<()> {
-        let client = self.get_tcp_id_client_mut(id)?;
-        client.is_streaming = is_streaming;
-        Some(())
+    pub fn get_client(&self, id: &MemberId) -> Option<&LobbyClient> {
+        self.clients.iter().find(|v| &v.id == id)
     }
 
-    fn get_tcp_id_client_mut(&mut self, id: &TcpId) -> Option<&mut LobbyClient> {
-        for client in self.clients.iter_mut() {
-            if &client.id == id {
-                return Some(client);
-            }
-        }
-        None
+    fn get_client_mut(&mut self, id: &MemberId) -> Option<&mut LobbyClient> {
+        self.clients.iter_mut().find(|v| &v.id == id)
     }
 
     fn add_client(&mut self, client: LobbyClient) {
-        self.remove_client(client.id);
+        self.remove_client(&client.id);
         self.clients.push(client);
     }
 
-    fn remove_client(&mut self, id: TcpId) {
-        if let Some((index, _)) = self.clients.iter().enumerate().find(|v| v.1.id == id) {
+    fn remove_client(&mut self, id: &MemberId) {
+        self.addrs.remove(id);
+        stop_watching(&mut self.clients, id);
+        if let Some((index, _)) = self.clients.iter().enumerate().find(|v| &v.1.id == id) {
             self.clients.swap_remove(index);
         }
     }
 }
 
+/// Snapshot of a lobby as of the event numbered `seq`
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LobbyInfoData {
+    pub seq: u64,
+    pub host: MemberId,
+    pub limits: LobbyLimits,
     pub clients: Vec<LobbyClient>,
 }
 
 impl LobbyInfoData {
-    pub fn new(clients: Vec<LobbyClient>) -> Self {
-        Self { clients }
+    pub fn new(seq: u64, host: MemberId, limits: LobbyLimits, clients: Vec<LobbyClient>) -> Self {
+        Self {
+            seq,
+            host,
+            limits,
+            clients,
+        }
     }
 
     pub fn from_lobby(lobby: &Lobby) -> Self {
-        Self::new(lobby.clients.clone())
+        Self::new(lobby.seq, lobby.host, lobby.limits, lobby.clients.clone())
+    }
+
+    /// Snapshot sent to `id`, without itself and without
+    /// the addresses of clients it exchanges no video with
+    pub fn for_client(lobby: &Lobby, id: MemberId) -> Self {
+        let mut info = Self::from_lobby(lobby).excluding_client(id);
+        for client in &mut info.clients {
+            if !exchange_video(&lobby.clients, &client.id, &id) {
+                client.udp_addr = None;
+            }
+        }
+        info
     }
 
-    pub fn excluding_client(mut self, id: TcpId) -> Self {
+    /// Brings the snapshot up to date with `delta`, deltas it already
+    /// contains are ignored. Returns false if deltas before this one were missed
+    pub fn apply(&mut self, delta: LobbyDelta) -> bool {
+        if delta.seq <= self.seq {
+            return true;
+        }
+        if delta.seq != self.seq + 1 {
+            return false;
+        }
+        self.seq = delta.seq;
+
+        match delta.event {
+            LobbyEvent::ClientJoined(client) => {
+                self.clients.retain(|v| v.id != client.id);
+                self.clients.push(client);
+            }
+            LobbyEvent::ClientLeft(id) => {
+                self.clients.retain(|v| v.id != id);
+                stop_watching(&mut self.clients, &id);
+            }
+            LobbyEvent::StreamStarted { id, metadata } => {
+                if let Some(client) = self.clients.iter_mut().find(|v| v.id == id) {
+                    client.stream = StreamState::Starting;
+                    client.stream_metadata = Some(metadata);
+                }
+            }
+            LobbyEvent::StreamChanged { id, state } => {
+                if let Some(client) = self.clients.iter_mut().find(|v| v.id == id) {
+                    client.stream = state;
+                }
+                if !state.is_active() {
+                    stop_watching(&mut self.clients, &id);
+                }
+            }
+            LobbyEvent::Subscribed { watcher, streamer } => {
+                if let Some(client) = self.clients.iter_mut().find(|v| v.id == watcher) {
+                    client.watching.insert(streamer);
+                }
+            }
+            LobbyEvent::Unsubscribed { watcher, streamer } => {
+                if let Some(client) = self.clients.iter_mut().find(|v| v.id == watcher) {
+                    client.watching.remove(&streamer);
+                }
+            }
+            LobbyEvent::UdpAddrChanged { id, udp_addr } => {
+                if let Some(client) = self.clients.iter_mut().find(|v| v.id == id) {
+                    client.udp_addr = udp_addr;
+                }
+            }
+            LobbyEvent::RelayPortChanged { id, relay_port } => {
+                if let Some(client) = self.clients.iter_mut().find(|v| v.id == id) {
+                    client.relay_port = Some(relay_port);
+                }
+            }
+            LobbyEvent::HostChanged(id) => self.host = id,
+            LobbyEvent::LimitsChanged(limits) => self.limits = limits,
+        }
+        true
+    }
+
+    pub fn excluding_client(mut self, id: MemberId) -> Self {
         let index = self.clients.iter().enumerate().find(|v| v.1.id == id);
         if let Some((index, _)) = index {
             self.clients.swap_remove(index);
@@ -155,3 +1224,376 @@ impl LobbyInfoData {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const LOBBY: &str = "test-lobby";
+
+    fn session(n: u8) -> TcpId {
+        [n; 32]
+    }
+
+    fn ip(n: u8) -> IpAddr {
+        Ipv4Addr::new(10, 0, 0, n).into()
+    }
+
+    fn udp_addr(n: u8) -> SocketAddr {
+        SocketAddr::new(ip(n), 5000 + n as u16)
+    }
+
+    fn profile() -> Profile {
+        Profile {
+            display_name: "test".to_string(),
+            color: 0,
+            avatar_hash: None,
+        }
+    }
+
+    fn metadata() -> StreamMetadata {
+        StreamMetadata {
+            codec: VideoCodec::H264,
+            payload_type: 96,
+            width: 1280,
+            height: 720,
+            framerate: 30,
+            bitrate_kbps: 2000,
+            title: String::new(),
+            source: SourceKind::Monitor,
+        }
+    }
+
+    fn join(lobbies: &mut Lobbies, n: u8) -> Joined {
+        let client = LobbyClient::new(session(n), profile(), None, StreamState::Idle);
+        lobbies
+            .join(LOBBY.to_string(), client, ip(n), &Credentials::default())
+            .unwrap()
+    }
+
+    /// Lobby of sessions `1..=members`, the first one hosts, each with its udp address registered
+    fn lobbies(members: u8) -> Lobbies {
+        let mut lobbies = Lobbies::new(8, LobbyLimits::default());
+        for n in 1..=members {
+            let joined = join(&mut lobbies, n);
+            lobbies
+                .set_client_udp_address(&joined.udp_token, udp_addr(n))
+                .unwrap();
+        }
+        lobbies
+    }
+
+    fn lobby(lobbies: &Lobbies) -> &Lobby {
+        lobbies.get(LOBBY).unwrap()
+    }
+
+    fn stream(lobbies: &mut Lobbies, n: u8) {
+        lobbies.start_stream(&session(n), metadata()).unwrap();
+        lobbies
+            .update_stream(&session(n), StreamUpdate::Live)
+            .unwrap();
+    }
+
+    fn udp_addr_changed(n: u8) -> LobbyDelta {
+        LobbyDelta {
+            seq: 1,
+            event: LobbyEvent::UdpAddrChanged {
+                id: member_id(&session(n)),
+                udp_addr: Some(udp_addr(n)),
+            },
+        }
+    }
+
+    fn address_seen_by(lobbies: &Lobbies, viewer: u8, peer: u8) -> Option<SocketAddr> {
+        let info = LobbyInfoData::for_client(lobby(lobbies), member_id(&session(viewer)));
+        let peer = member_id(&session(peer));
+        info.clients.iter().find(|v| v.id == peer).unwrap().udp_addr
+    }
+
+    #[test]
+    fn non_watcher_gets_no_address_of_streamer() {
+        let mut lobbies = lobbies(3);
+        stream(&mut lobbies, 1);
+        lobbies
+            .subscribe(&session(2), &member_id(&session(1)))
+            .unwrap();
+
+        assert_eq!(address_seen_by(&lobbies, 3, 1), None);
+        assert_eq!(address_seen_by(&lobbies, 1, 3), None);
+
+        let delta = udp_addr_changed(1);
+        let LobbyEvent::UdpAddrChanged { udp_addr, .. } = delta
+            .for_client(lobby(&lobbies), &member_id(&session(3)))
+            .event
+        else {
+            unreachable!();
+        };
+        assert_eq!(udp_addr, None);
+    }
+
+    #[test]
+    fn subscription_shares_both_addresses() {
+        let mut lobbies = lobbies(2);
+        stream(&mut lobbies, 1);
+        assert_eq!(address_seen_by(&lobbies, 2, 1), None);
+
+        lobbies
+            .subscribe(&session(2), &member_id(&session(1)))
+            .unwrap();
+        assert_eq!(address_seen_by(&lobbies, 2, 1), Some(udp_addr(1)));
+        assert_eq!(address_seen_by(&lobbies, 1, 2), Some(udp_addr(2)));
+
+        let LobbyEvent::UdpAddrChanged { udp_addr: addr, .. } = udp_addr_changed(2)
+            .for_client(lobby(&lobbies), &member_id(&session(1)))
+            .event
+        else {
+            unreachable!();
+        };
+        assert_eq!(addr, Some(udp_addr(2)));
+    }
+
+    #[test]
+    fn joiner_address_reaches_no_one() {
+        let lobbies = lobbies(2);
+        let client = LobbyClient::new(session(3), profile(), Some(udp_addr(3)), StreamState::Idle);
+        let delta = LobbyDelta {
+            seq: 1,
+            event: LobbyEvent::ClientJoined(client),
+        };
+        for viewer in [1, 2] {
+            let LobbyEvent::ClientJoined(client) = delta
+                .for_client(lobby(&lobbies), &member_id(&session(viewer)))
+                .event
+            else {
+                unreachable!();
+            };
+            assert_eq!(client.udp_addr, None);
+        }
+    }
+
+    #[test]
+    fn only_the_udp_token_registers_an_address() {
+        let mut lobbies = lobbies(1);
+        let joined = join(&mut lobbies, 2);
+
+        // the session id of a member doesn't work in place of its token
+        assert!(
+            lobbies
+                .set_client_udp_address(&session(2), udp_addr(1))
+                .is_none()
+        );
+        let (_, delta) = lobbies
+            .set_client_udp_address(&joined.udp_token, udp_addr(2))
+            .unwrap();
+        let LobbyEvent::UdpAddrChanged { id, .. } = delta.event else {
+            unreachable!();
+        };
+        assert_eq!(id, member_id(&session(2)));
+
+        // leaving revokes it
+        lobbies.cleanup(&session(2));
+        assert!(
+            lobbies
+                .set_client_udp_address(&joined.udp_token, udp_addr(2))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn leaving_streamer_clears_its_watchers() {
+        let mut lobbies = lobbies(3);
+        stream(&mut lobbies, 2);
+        lobbies
+            .subscribe(&session(3), &member_id(&session(2)))
+            .unwrap();
+
+        lobbies.cleanup(&session(2));
+        let watcher = lobby(&lobbies).get_client(&member_id(&session(3))).unwrap();
+        assert!(watcher.watching.is_empty());
+    }
+
+    #[test]
+    fn ended_stream_clears_its_watchers() {
+        let mut lobbies = lobbies(2);
+        stream(&mut lobbies, 1);
+        lobbies
+            .subscribe(&session(2), &member_id(&session(1)))
+            .unwrap();
+        let mut model = LobbyInfoData::from_lobby(lobby(&lobbies));
+
+        let (_, delta) = lobbies.stop_stream(&session(1)).unwrap();
+        let watcher = member_id(&session(2));
+        assert!(
+            lobby(&lobbies)
+                .get_client(&watcher)
+                .unwrap()
+                .watching
+                .is_empty()
+        );
+        assert_eq!(address_seen_by(&lobbies, 2, 1), None);
+
+        assert!(model.apply(delta));
+        let watcher = model.clients.iter().find(|v| v.id == watcher).unwrap();
+        assert!(watcher.watching.is_empty());
+    }
+
+    fn model(seq: u64, members: u8) -> LobbyInfoData {
+        let clients = (1..=members)
+            .map(|n| LobbyClient::new(session(n), profile(), None, StreamState::Idle))
+            .collect();
+        LobbyInfoData::new(seq, member_id(&session(1)), LobbyLimits::default(), clients)
+    }
+
+    fn client_left(seq: u64, n: u8) -> LobbyDelta {
+        LobbyDelta {
+            seq,
+            event: LobbyEvent::ClientLeft(member_id(&session(n))),
+        }
+    }
+
+    #[test]
+    fn apply_ignores_stale_deltas() {
+        let mut model = model(5, 2);
+        assert!(model.apply(client_left(5, 2)));
+        assert!(model.apply(client_left(3, 2)));
+        assert_eq!(model.seq, 5);
+        assert_eq!(model.clients.len(), 2);
+    }
+
+    #[test]
+    fn apply_takes_the_next_delta() {
+        let mut model = model(5, 2);
+        assert!(model.apply(client_left(6, 2)));
+        assert_eq!(model.seq, 6);
+        assert_eq!(model.clients.len(), 1);
+    }
+
+    #[test]
+    fn apply_refuses_a_gap() {
+        let mut model = model(5, 2);
+        assert!(!model.apply(client_left(7, 2)));
+        assert_eq!(model.seq, 5);
+        assert_eq!(model.clients.len(), 2);
+    }
+
+    #[test]
+    fn apply_replaces_a_client_that_joins_again() {
+        let mut model = model(5, 2);
+        let mut client = LobbyClient::new(session(2), profile(), None, StreamState::Idle);
+        client.profile.display_name = "renamed".to_string();
+        let delta = LobbyDelta {
+            seq: 6,
+            event: LobbyEvent::ClientJoined(client),
+        };
+
+        assert!(model.apply(delta));
+        let joined = member_id(&session(2));
+        let clients = model.clients.iter().filter(|v| v.id == joined);
+        assert_eq!(
+            clients
+                .map(|v| v.profile.display_name.as_str())
+                .collect::<Vec<_>>(),
+            ["renamed"]
+        );
+    }
+
+    fn code<T: std::fmt::Debug>(ret: Result<T, RpcError>) -> RpcErrorCode {
+        ret.unwrap_err().code
+    }
+
+    #[test]
+    fn only_the_host_manages_the_lobby() {
+        let mut lobbies = lobbies(3);
+        stream(&mut lobbies, 3);
+        let (member, other) = (session(2), member_id(&session(3)));
+
+        assert_eq!(code(lobbies.kick(&member, &other)), RpcErrorCode::NotHost);
+        assert_eq!(code(lobbies.ban(&member, &other)), RpcErrorCode::NotHost);
+        assert_eq!(
+            code(lobbies.stop_client_stream(&member, &other)),
+            RpcErrorCode::NotHost
+        );
+        assert_eq!(
+            code(lobbies.transfer_host(&member, &other)),
+            RpcErrorCode::NotHost
+        );
+        assert_eq!(
+            code(lobbies.set_limits(&member, LobbyLimits::default())),
+            RpcErrorCode::NotHost
+        );
+        assert_eq!(
+            code(lobbies.set_access(&member, None, true)),
+            RpcErrorCode::NotHost
+        );
+        assert_eq!(
+            code(lobbies.create_invite(&member, time::Duration::from_secs(60))),
+            RpcErrorCode::NotHost
+        );
+
+        // nothing changed
+        assert_eq!(lobby(&lobbies).clients.len(), 3);
+        assert_eq!(lobby(&lobbies).host(), &member_id(&session(1)));
+        lobbies.kick(&session(1), &other).unwrap();
+    }
+
+    fn rejoin(lobbies: &mut Lobbies, n: u8, addr: IpAddr) -> Result<Joined, RpcError> {
+        let client = LobbyClient::new(session(n), profile(), None, StreamState::Idle);
+        lobbies.join(LOBBY.to_string(), client, addr, &Credentials::default())
+    }
+
+    #[test]
+    fn banned_session_and_address_stay_out() {
+        let mut lobbies = lobbies(2);
+        lobbies.ban(&session(1), &member_id(&session(2))).unwrap();
+
+        // same session from elsewhere, another session from the same address
+        assert_eq!(code(rejoin(&mut lobbies, 2, ip(9))), RpcErrorCode::Banned);
+        assert_eq!(code(rejoin(&mut lobbies, 3, ip(2))), RpcErrorCode::Banned);
+        rejoin(&mut lobbies, 4, ip(4)).unwrap();
+    }
+
+    #[test]
+    fn ban_spares_the_address_of_the_host() {
+        let mut lobbies = lobbies(1);
+        // behind the same nat as the host
+        rejoin(&mut lobbies, 2, ip(1)).unwrap();
+        lobbies.ban(&session(1), &member_id(&session(2))).unwrap();
+
+        assert_eq!(code(rejoin(&mut lobbies, 2, ip(9))), RpcErrorCode::Banned);
+        rejoin(&mut lobbies, 3, ip(1)).unwrap();
+    }
+
+    #[test]
+    fn leaving_host_hands_the_lobby_over() {
+        let mut lobbies = lobbies(3);
+        let (_, deltas) = lobbies.cleanup(&session(1)).unwrap();
+
+        let host = *lobby(&lobbies).host();
+        assert!(lobby(&lobbies).get_client(&host).is_some());
+        assert!(matches!(
+            deltas.last().unwrap().event,
+            LobbyEvent::HostChanged(id) if id == host
+        ));
+
+        // switching lobbies leaves like a disconnect does
+        let host_session = (1..=3).map(session).find(|v| member_id(v) == host).unwrap();
+        let client = LobbyClient::new(host_session, profile(), None, StreamState::Idle);
+        let joined = lobbies
+            .join("other".to_string(), client, ip(1), &Credentials::default())
+            .unwrap();
+        assert_eq!(joined.left.as_deref(), Some(LOBBY));
+        let last = lobby(&lobbies).clients[0].id;
+        assert_eq!(lobby(&lobbies).host(), &last);
+        assert!(matches!(
+            joined.left_deltas.last().unwrap().event,
+            LobbyEvent::HostChanged(id) if id == last
+        ));
+
+        // the last one out closes it
+        let last_session = (1..=3).map(session).find(|v| member_id(v) == last).unwrap();
+        assert!(lobbies.cleanup(&last_session).is_none());
+        assert!(lobbies.get(LOBBY).is_none());
+    }
+}
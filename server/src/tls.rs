@@ -0,0 +1,254 @@
+use std::{
+    fs,
+    io::{self, prelude::*},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use futures_rustls::{
+    TlsAcceptor, TlsConnector,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject},
+    rustls::{
+        self, DigitallySignedStruct, SignatureScheme,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{self, CryptoProvider},
+    },
+};
+
+pub type Fingerprint = [u8; 32];
+
+pub fn fingerprint(cert: &CertificateDer<'_>) -> Fingerprint {
+    let digest = ring::digest::digest(&ring::digest::SHA256, cert.as_ref());
+    let mut fingerprint: Fingerprint = [0; 32];
+    fingerprint.copy_from_slice(digest.as_ref());
+    fingerprint
+}
+
+pub fn fingerprint_to_hex(fingerprint: &Fingerprint) -> String {
+    fingerprint.iter().map(|v| format!("{:02x}", v)).collect()
+}
+
+/// Parses a sha256 fingerprint, `:` separators are allowed
+pub fn fingerprint_from_hex(hex: &str) -> anyhow::Result<Fingerprint> {
+    let hex = hex.trim().replace(':', "");
+    // also keeps the slicing below on char boundaries
+    if hex.len() != 64 || !hex.bytes().all(|v| v.is_ascii_hexdigit()) {
+        anyhow::bail!("fingerprint should be 32 hex encoded bytes");
+    }
+
+    let mut fingerprint: Fingerprint = [0; 32];
+    for (i, v) in fingerprint.iter_mut().enumerate() {
+        *v = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+    }
+    Ok(fingerprint)
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+/// Fingerprint of the first certificate in the pem file, for clients to pin
+pub fn cert_fingerprint(cert_path: &Path) -> anyhow::Result<Fingerprint> {
+    let cert = CertificateDer::from_pem_file(cert_path)?;
+    Ok(fingerprint(&cert))
+}
+
+pub fn acceptor(cert_path: &Path, key_path: &Path) -> anyhow::Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert_path)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key_path)?;
+
+    let config = rustls::ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// How the client decides to trust the server certificate
+#[derive(Debug, Clone)]
+pub enum Trust {
+    /// Verify the chain against the ca certificates in this pem file
+    Ca(PathBuf),
+    /// Only accept a server certificate with this sha256 fingerprint
+    Pinned(Fingerprint),
+    /// Remember the fingerprint on first connect in this file,
+    /// reject a different one afterwards
+    Tofu(PathBuf),
+}
+
+#[derive(Debug)]
+enum Pin {
+    Fingerprint(Fingerprint),
+    KnownHosts(PathBuf, Mutex<()>),
+}
+
+#[derive(Debug)]
+struct PinnedVerifier {
+    host: String,
+    pin: Pin,
+    provider: Arc<CryptoProvider>,
+}
+
+impl PinnedVerifier {
+    fn read_known_host(&self, path: &Path) -> io::Result<Option<Fingerprint>> {
+        let known_hosts = match fs::read_to_string(path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        Ok(known_hosts.lines().find_map(|line| {
+            let (host, fingerprint) = line.split_once(' ')?;
+            if host != self.host {
+                return None;
+            }
+            fingerprint_from_hex(fingerprint).ok()
+        }))
+    }
+
+    fn write_known_host(&self, path: &Path, fingerprint: &Fingerprint) -> io::Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        writeln!(file, "{} {}", self.host, fingerprint_to_hex(fingerprint))
+    }
+
+    fn verify_fingerprint(&self, fingerprint: Fingerprint) -> Result<(), rustls::Error> {
+        let expected = match &self.pin {
+            Pin::Fingerprint(v) => *v,
+            Pin::KnownHosts(path, lock) => {
+                let _lock = lock.lock().unwrap();
+                let known = self
+                    .read_known_host(path)
+                    .map_err(|e| rustls::Error::General(e.to_string()))?;
+                match known {
+                    Some(v) => v,
+                    None => {
+                        log::warn!(
+                            "tls: trusting {} on first use: {}",
+                            self.host,
+                            fingerprint_to_hex(&fingerprint)
+                        );
+                        self.write_known_host(path, &fingerprint)
+                            .map_err(|e| rustls::Error::General(e.to_string()))?;
+                        fingerprint
+                    }
+                }
+            }
+        };
+
+        if expected != fingerprint {
+            return Err(rustls::Error::General(format!(
+                "certificate fingerprint of {} changed: expected {}, got {}",
+                self.host,
+                fingerprint_to_hex(&expected),
+                fingerprint_to_hex(&fingerprint)
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.verify_fingerprint(fingerprint(end_entity))?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[derive(Clone)]
+pub struct TlsClient {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl TlsClient {
+    pub fn new(host: &str, port: u16, trust: Trust) -> anyhow::Result<Self> {
+        let provider = provider();
+        let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+
+        let config = match trust {
+            Trust::Ca(path) => {
+                let mut roots = rustls::RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(path)? {
+                    roots.add(cert?)?;
+                }
+                builder.with_root_certificates(roots)
+            }
+            Trust::Pinned(fingerprint) => {
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                        host: format!("{}:{}", host, port),
+                        pin: Pin::Fingerprint(fingerprint),
+                        provider,
+                    }))
+            }
+            Trust::Tofu(path) => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                    host: format!("{}:{}", host, port),
+                    pin: Pin::KnownHosts(path, Mutex::new(())),
+                    provider,
+                })),
+        }
+        .with_no_client_auth();
+
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name: ServerName::try_from(host.to_string())?,
+        })
+    }
+
+    pub fn connector(&self) -> &TlsConnector {
+        &self.connector
+    }
+
+    pub fn server_name(&self) -> ServerName<'static> {
+        self.server_name.clone()
+    }
+}
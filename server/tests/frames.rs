@@ -0,0 +1,247 @@
+use std::{io, time};
+
+use futures::prelude::*;
+use proptest::prelude::*;
+use server::{
+    codec::Codec,
+    mux::{self, DEFAULT_MAX_FRAME_SIZE, MuxChannel},
+    rpc::{
+        CallCode, PING_CODE, RpcCode, RpcError, RpcErrorCode, RpcNotifyCode, RpcReader, RpcWriter,
+    },
+};
+
+fn codec() -> impl Strategy<Value = Codec> {
+    prop_oneof![Just(Codec::Json), Just(Codec::MessagePack)]
+}
+
+fn write_request(buf: &mut Vec<u8>, code: u32, id: u32, data: &[u8]) {
+    smol::block_on(RpcWriter::new(buf, Codec::Json).send_request(code, id, data)).unwrap();
+}
+
+fn write_response(buf: &mut Vec<u8>, codec: Codec, id: u32, ret: Result<Vec<u8>, RpcError>) {
+    smol::block_on(RpcWriter::new(buf, codec).send_response(id, ret)).unwrap();
+}
+
+fn header(code: u32, id: u32, len: u32) -> Vec<u8> {
+    [code, id, len]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect()
+}
+
+/// Highest code of each service, bump along with new calls
+const LAST_RPC_CODE: u32 = 19;
+const LAST_NOTIFY_CODE: u32 = 4;
+
+/// Both ends of a muxed loopback connection, as (rpc, notify)
+async fn mux_pair() -> ((MuxChannel, MuxChannel), (MuxChannel, MuxChannel)) {
+    let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (client, server) =
+        future::join(smol::net::TcpStream::connect(addr), listener.accept()).await;
+    (mux::mux(client.unwrap()), mux::mux(server.unwrap().0))
+}
+
+async fn send_frame(channel: &mut MuxChannel, data: &[u8]) {
+    channel.write_all(data).await.unwrap();
+    channel.flush().await.unwrap();
+}
+
+/// Bytes read from `channel`, empty at eof, `None` if nothing came in time
+async fn read_timeout(channel: &mut MuxChannel) -> Option<Vec<u8>> {
+    let mut buf = [0; 64];
+    let read = async { Some(channel.read(&mut buf).await.unwrap()) };
+    let timeout = async {
+        smol::Timer::after(time::Duration::from_secs(2)).await;
+        None
+    };
+    let len = smol::future::or(read, timeout).await?;
+    Some(buf[..len].to_vec())
+}
+
+#[test]
+fn unread_channel_doesnt_hold_up_the_other() {
+    smol::block_on(async {
+        let ((mut rpc, mut notify), (mut peer_rpc, mut peer_notify)) = mux_pair().await;
+        for i in 0..4 {
+            send_frame(&mut rpc, &[i]).await;
+        }
+        send_frame(&mut notify, b"notify").await;
+
+        assert_eq!(read_timeout(&mut peer_notify).await.unwrap(), b"notify");
+        for i in 0..4 {
+            assert_eq!(read_timeout(&mut peer_rpc).await.unwrap(), [i]);
+        }
+    });
+}
+
+#[test]
+fn flooding_an_unread_channel_closes_the_connection() {
+    smol::block_on(async {
+        let ((mut rpc, _notify), (_peer_rpc, mut peer_notify)) = mux_pair().await;
+        // writes start failing once the peer hangs up
+        for i in 0..32 {
+            let _ = rpc.write_all(&[i]).await;
+            let _ = rpc.flush().await;
+        }
+
+        assert_eq!(read_timeout(&mut peer_notify).await.unwrap(), b"");
+    });
+}
+
+#[test]
+fn every_code_dispatches() {
+    for value in 1..=LAST_RPC_CODE {
+        assert_eq!(RpcCode::from(value) as u32, value);
+    }
+    assert_eq!(RpcCode::from(LAST_RPC_CODE + 1), RpcCode::Unknown);
+    for value in 1..=LAST_NOTIFY_CODE {
+        assert_eq!(RpcNotifyCode::from(value) as u32, value);
+    }
+    assert_eq!(
+        RpcNotifyCode::from(LAST_NOTIFY_CODE + 1),
+        RpcNotifyCode::Unknown
+    );
+}
+
+#[test]
+fn ping_code_dispatch() {
+    assert_eq!(RpcCode::from(PING_CODE), RpcCode::Ping);
+    assert_eq!(RpcNotifyCode::from(PING_CODE), RpcNotifyCode::Ping);
+    assert_eq!(RpcCode::Ping.max_payload_len(), 0);
+    assert_eq!(RpcNotifyCode::Ping.max_payload_len(), 0);
+}
+
+proptest! {
+    #[test]
+    fn rpc_code_dispatch(value in any::<u32>()) {
+        let code = RpcCode::from(value);
+        match value {
+            1..=LAST_RPC_CODE | PING_CODE => prop_assert_eq!(code as u32, value),
+            _ => prop_assert_eq!(code, RpcCode::Unknown),
+        }
+    }
+
+    #[test]
+    fn rpc_notify_code_dispatch(value in any::<u32>()) {
+        let code = RpcNotifyCode::from(value);
+        match value {
+            1..=LAST_NOTIFY_CODE | PING_CODE => prop_assert_eq!(code as u32, value),
+            _ => prop_assert_eq!(code, RpcNotifyCode::Unknown),
+        }
+    }
+
+    #[test]
+    fn request_roundtrip(
+        code in 0u32..4,
+        id in any::<u32>(),
+        data in prop::collection::vec(any::<u8>(), 0..2048),
+        next_id in any::<u32>(),
+    ) {
+        let mut buf = Vec::new();
+        write_request(&mut buf, code, id, &data);
+        write_request(&mut buf, RpcCode::StartStream as u32, next_id, b"{}");
+
+        let mut reader = RpcReader::new(buf.as_slice(), Codec::Json);
+        let request = smol::block_on(reader.recv_request::<RpcCode>()).unwrap();
+        prop_assert_eq!(request.code, RpcCode::from(code));
+        prop_assert_eq!(request.id, id);
+        if data.len() <= request.code.max_payload_len() {
+            prop_assert_eq!(request.data.unwrap(), data);
+        } else {
+            prop_assert_eq!(request.data.unwrap_err().code, RpcErrorCode::PayloadTooLarge);
+        }
+
+        // refused payloads are skipped, the following request still decodes
+        let next = smol::block_on(reader.recv_request::<RpcCode>()).unwrap();
+        prop_assert_eq!(next.code, RpcCode::StartStream);
+        prop_assert_eq!(next.id, next_id);
+        prop_assert_eq!(next.data.unwrap(), b"{}".to_vec());
+    }
+
+    #[test]
+    fn response_roundtrip(
+        codec in codec(),
+        id in any::<u32>(),
+        data in prop::collection::vec(any::<u8>(), 0..2048),
+        message in ".*",
+        is_error in any::<bool>(),
+    ) {
+        let ret = match is_error {
+            true => Err(RpcError::new(RpcErrorCode::NotInLobby, message)),
+            false => Ok(data),
+        };
+        let mut buf = Vec::new();
+        write_response(&mut buf, codec, id, ret.clone());
+
+        let mut reader = RpcReader::new(buf.as_slice(), codec);
+        let (read_id, read_ret) = smol::block_on(reader.recv_response()).unwrap();
+        prop_assert_eq!(read_id, id);
+        match (read_ret, ret) {
+            (Ok(read), Ok(data)) => prop_assert_eq!(read, data),
+            (Err(read), Err(error)) => {
+                prop_assert_eq!(read.code, error.code);
+                prop_assert_eq!(read.message, error.message);
+                prop_assert_eq!(read.retryable, error.retryable);
+            }
+            (read, _) => prop_assert!(false, "status changed: {:?}", read),
+        }
+    }
+
+    #[test]
+    fn oversized_length_is_rejected_before_reading(
+        len in (DEFAULT_MAX_FRAME_SIZE as u32 + 1)..=u32::MAX,
+    ) {
+        let buf = header(RpcCode::JoinLobby as u32, 0, len);
+        let mut reader = RpcReader::new(buf.as_slice(), Codec::Json);
+        let err = smol::block_on(reader.recv_request::<RpcCode>()).unwrap_err();
+        prop_assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_payload_is_an_error(len in 1u32..1024, sent in 0usize..1024) {
+        let sent = sent.min(len as usize - 1);
+        let mut buf = header(RpcCode::JoinLobby as u32, 0, len);
+        buf.extend(std::iter::repeat_n(0, sent));
+        let mut reader = RpcReader::new(buf.as_slice(), Codec::Json);
+        let err = smol::block_on(reader.recv_request::<RpcCode>()).unwrap_err();
+        prop_assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic(
+        bytes in prop::collection::vec(any::<u8>(), 0..4096),
+        max_frame_size in 0usize..4096,
+    ) {
+        smol::block_on(async {
+            let mut reader = RpcReader::new(bytes.as_slice(), Codec::MessagePack)
+                .with_max_frame_size(max_frame_size);
+            while reader.recv_request::<RpcNotifyCode>().await.is_ok() {}
+
+            let mut reader = RpcReader::new(bytes.as_slice(), Codec::MessagePack)
+                .with_max_frame_size(max_frame_size);
+            while reader.recv_response().await.is_ok() {}
+
+            let mut frames = bytes.as_slice();
+            while mux::read_frame(&mut frames, max_frame_size).await.is_ok() {}
+        });
+    }
+
+    #[test]
+    fn mux_frame_roundtrip(
+        channel in any::<u32>(),
+        data in prop::collection::vec(any::<u8>(), 0..4096),
+        max_frame_size in 0usize..8192,
+    ) {
+        let mut buf = channel.to_le_bytes().to_vec();
+        buf.extend((data.len() as u32).to_le_bytes());
+        buf.extend(&data);
+
+        let frame = smol::block_on(mux::read_frame(buf.as_slice(), max_frame_size));
+        if data.len() <= max_frame_size {
+            prop_assert_eq!(frame.unwrap(), (channel, data));
+        } else {
+            prop_assert_eq!(frame.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::{fs, path::PathBuf, time};
+
+use futures::{channel::mpsc, future};
+use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+use server::{
+    admission::{Admission, AdmissionConfig},
+    conn::{TcpSendReceive, TcpSendReceiveClient, TcpSenderReceiver},
+    session::Sessions,
+    tls::{self, Trust},
+};
+
+const HOST: &str = "localhost";
+
+/// Fresh directory for the files of one test
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bss-tls-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+struct ServerCert {
+    cert_pem: String,
+    key_pem: String,
+}
+
+fn new_ca() -> CertifiedIssuer<'static, KeyPair> {
+    let mut params = CertificateParams::new(Vec::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap()
+}
+
+fn ca_signed(ca: &CertifiedIssuer<'static, KeyPair>) -> ServerCert {
+    let key = KeyPair::generate().unwrap();
+    let cert = CertificateParams::new(vec![HOST.to_string()])
+        .unwrap()
+        .signed_by(&key, ca)
+        .unwrap();
+    ServerCert {
+        cert_pem: cert.pem(),
+        key_pem: key.serialize_pem(),
+    }
+}
+
+fn self_signed() -> ServerCert {
+    let key = KeyPair::generate().unwrap();
+    let cert = CertificateParams::new(vec![HOST.to_string()])
+        .unwrap()
+        .self_signed(&key)
+        .unwrap();
+    ServerCert {
+        cert_pem: cert.pem(),
+        key_pem: key.serialize_pem(),
+    }
+}
+
+/// Accepts tls connections with `cert` until the task is dropped
+struct TestServer {
+    port: u16,
+    cert_path: PathBuf,
+    _accept_rx: mpsc::Receiver<TcpSenderReceiver>,
+    listen: smol::Task<anyhow::Result<()>>,
+}
+
+async fn serve(dir: &std::path::Path, cert: &ServerCert, port: u16) -> TestServer {
+    let cert_path = dir.join(format!("cert-{port}.pem"));
+    let key_path = dir.join(format!("key-{port}.pem"));
+    fs::write(&cert_path, &cert.cert_pem).unwrap();
+    fs::write(&key_path, &cert.key_pem).unwrap();
+
+    let (accept_tx, accept_rx) = mpsc::channel(8);
+    let conn = TcpSendReceive::new(
+        "127.0.0.1",
+        port,
+        Admission::new(AdmissionConfig::default()),
+        Sessions::new(time::Duration::from_secs(30)),
+        accept_tx,
+    )
+    .await
+    .unwrap()
+    .with_tls(tls::acceptor(&cert_path, &key_path).unwrap());
+    TestServer {
+        port: conn.local_addr().unwrap().port(),
+        cert_path,
+        _accept_rx: accept_rx,
+        listen: smol::spawn(conn.listen()),
+    }
+}
+
+async fn connect(port: u16, trust: Trust) -> anyhow::Result<TcpSenderReceiver> {
+    TcpSendReceiveClient::new(HOST.to_string(), port)
+        .with_tls(trust)?
+        .create()
+        .await
+}
+
+#[test]
+fn ca_signed_handshake() {
+    smol::block_on(async {
+        let dir = test_dir("ca");
+        let ca = new_ca();
+        let ca_path = dir.join("ca.pem");
+        fs::write(&ca_path, ca.pem()).unwrap();
+        let server = serve(&dir, &ca_signed(&ca), 0).await;
+
+        connect(server.port, Trust::Ca(ca_path)).await.unwrap();
+
+        // signed by someone else
+        let other_path = dir.join("other-ca.pem");
+        fs::write(&other_path, new_ca().pem()).unwrap();
+        assert!(connect(server.port, Trust::Ca(other_path)).await.is_err());
+    });
+}
+
+#[test]
+fn pinned_fingerprint() {
+    smol::block_on(async {
+        let dir = test_dir("pinned");
+        let server = serve(&dir, &self_signed(), 0).await;
+        let fingerprint = tls::cert_fingerprint(&server.cert_path).unwrap();
+
+        connect(server.port, Trust::Pinned(fingerprint))
+            .await
+            .unwrap();
+
+        let mut wrong = fingerprint;
+        wrong[0] ^= 1;
+        let e = connect(server.port, Trust::Pinned(wrong))
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("fingerprint"), "{e}");
+    });
+}
+
+#[test]
+fn tofu_rejects_changed_key() {
+    smol::block_on(async {
+        let dir = test_dir("tofu");
+        let known_hosts = dir.join("known_hosts");
+        let server = serve(&dir, &self_signed(), 0).await;
+        let port = server.port;
+
+        // trusted on first use, then remembered
+        connect(port, Trust::Tofu(known_hosts.clone()))
+            .await
+            .unwrap();
+        let recorded = fs::read_to_string(&known_hosts).unwrap();
+        let fingerprint = tls::cert_fingerprint(&server.cert_path).unwrap();
+        assert_eq!(
+            recorded.trim(),
+            format!("{HOST}:{port} {}", tls::fingerprint_to_hex(&fingerprint))
+        );
+        connect(port, Trust::Tofu(known_hosts.clone()))
+            .await
+            .unwrap();
+
+        // same address, new key
+        // closes the listener before the port is taken again
+        server.listen.cancel().await;
+        let _server = serve(&dir, &self_signed(), port).await;
+        let e = connect(port, Trust::Tofu(known_hosts.clone()))
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("changed"), "{e}");
+        assert_eq!(fs::read_to_string(&known_hosts).unwrap(), recorded);
+    });
+}
+
+#[test]
+fn fingerprint_from_non_ascii_hex() {
+    // as long as a valid fingerprint, but in two byte chars
+    assert!(tls::fingerprint_from_hex(&"é".repeat(32)).is_err());
+    assert!(tls::fingerprint_from_hex(&"+a".repeat(32)).is_err());
+    let fingerprint = [0xab; 32];
+    assert_eq!(
+        tls::fingerprint_from_hex(&tls::fingerprint_to_hex(&fingerprint)).unwrap(),
+        fingerprint
+    );
+}
+
+#[test]
+fn silent_server_times_out() {
+    smol::block_on(async {
+        // accepts the connection and never answers the tls handshake
+        let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _accept = smol::spawn(async move {
+            let _stream = listener.accept().await;
+            future::pending::<()>().await
+        });
+
+        let started = time::Instant::now();
+        let e = TcpSendReceiveClient::new(HOST.to_string(), port)
+            .with_handshake_timeout(time::Duration::from_millis(200))
+            .with_tls(Trust::Pinned([0; 32]))
+            .unwrap()
+            .create()
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("handshake"), "{e}");
+        assert!(started.elapsed() < time::Duration::from_secs(5));
+    });
+}